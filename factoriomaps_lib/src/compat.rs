@@ -0,0 +1,155 @@
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Factorio release, parsed from `major.minor.patch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+impl std::str::FromStr for Version {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid Factorio version \"{s}\", expected major.minor.patch");
+        let parts = s
+            .split('.')
+            .map(|p| p.parse::<u32>().map_err(|_| err()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [major, minor, patch] = parts[..] else {
+            return Err(err());
+        };
+        Ok(Version(major, minor, patch))
+    }
+}
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// How screenshots are captured from a range of Factorio versions
+#[derive(Debug)]
+pub struct Abi {
+    pub versions: RangeInclusive<Version>,
+    /// Detour target skipping BMP encoding, screenshots are decoded from the BMP files Factorio
+    /// writes if `None`, if the symbol is missing from the binary or if the first bitmap doesn't
+    /// match the layout
+    pub save_to_file: Option<SaveToFile>,
+}
+
+/// `MemoryBitmap::saveToFile(Filesystem::Path const&, unsigned char) const`
+#[derive(Debug)]
+pub struct SaveToFile {
+    pub symbol: &'static str,
+    pub bitmap: BitmapLayout,
+    pub path: StringLayout,
+}
+
+/// Byte offsets of the `MemoryBitmap` fields
+#[derive(Debug)]
+pub struct BitmapLayout {
+    /// `u32`
+    pub width: usize,
+    /// `u32`
+    pub height: usize,
+    /// Pointer to RGBA pixels
+    pub data: usize,
+    /// `usize` bytes of pixels
+    pub data_size: usize,
+    /// `bool`
+    pub flipped: usize,
+}
+
+/// Byte offsets of the `std::string` fields of a `Filesystem::Path`
+#[derive(Debug)]
+pub struct StringLayout {
+    /// Pointer to UTF-8 bytes
+    pub data: usize,
+    /// `usize` bytes
+    pub length: usize,
+}
+
+/// `MemoryBitmap` and `Filesystem::Path` of 1.1, the first bitmap is checked against it
+const SAVE_TO_FILE_1_1: SaveToFile = SaveToFile {
+    symbol: "_ZNK12MemoryBitmap10saveToFileERKN10Filesystem4PathEh",
+    bitmap: BitmapLayout {
+        width: 0,
+        height: 4,
+        data: 8,
+        data_size: 24,
+        flipped: 32,
+    },
+    path: StringLayout { data: 0, length: 8 },
+};
+
+/// Every version the lib is known to work with
+pub const KNOWN: &[Abi] = &[
+    Abi {
+        versions: Version(1, 1, 0)..=Version(1, 1, 110),
+        save_to_file: Some(SAVE_TO_FILE_1_1),
+    },
+    // the MemoryBitmap layout of 2.0 hasn't been verified, every release decodes the BMP files
+    // which don't depend on the binary
+    Abi {
+        versions: Version(2, 0, 0)..=Version(2, 0, u32::MAX),
+        save_to_file: None,
+    },
+];
+
+/// Returns how to capture screenshots from `version`
+pub fn abi(version: Version) -> Result<&'static Abi, String> {
+    KNOWN
+        .iter()
+        .find(|abi| abi.versions.contains(&version))
+        .ok_or_else(|| {
+            let known: Vec<String> = KNOWN
+                .iter()
+                .map(|abi| match *abi.versions.end() {
+                    Version(major, minor, u32::MAX) => {
+                        format!("{}-{major}.{minor}.*", abi.versions.start())
+                    }
+                    end => format!("{}-{end}", abi.versions.start()),
+                })
+                .collect();
+            format!(
+                "unsupported Factorio version {version}, expected one of: {}",
+                known.join(", ")
+            )
+        })
+}
+
+/// Reads the version of the Factorio install at `factorio` from `data/base/info.json`
+pub fn detect(factorio: &Path) -> Result<Version, String> {
+    #[derive(serde::Deserialize)]
+    struct ModInfo {
+        version: String,
+    }
+    let path = factorio.join("data/base/info.json");
+    let data = std::fs::read(&path)
+        .map_err(|e| format!("can't detect the Factorio version from {path:?}: {e}"))?;
+    let info: ModInfo = serde_json::from_slice(&data)
+        .map_err(|e| format!("can't detect the Factorio version from {path:?}: {e}"))?;
+    info.version.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version() {
+        assert_eq!("1.1.110".parse(), Ok(Version(1, 1, 110)));
+        assert_eq!("2.0.0".parse(), Ok(Version(2, 0, 0)));
+        assert!("1.1".parse::<Version>().is_err());
+        assert!("1.1.1.1".parse::<Version>().is_err());
+        assert!("1.x.0".parse::<Version>().is_err());
+        assert_eq!(Version(1, 1, 7).to_string(), "1.1.7");
+    }
+
+    #[test]
+    fn abi_lookup() {
+        assert!(abi(Version(1, 1, 0)).unwrap().save_to_file.is_some());
+        assert!(abi(Version(1, 1, 110)).is_ok());
+        assert!(abi(Version(1, 1, 111)).is_err());
+        assert!(abi(Version(2, 0, 72)).unwrap().save_to_file.is_none());
+        assert!(abi(Version(1, 0, 0)).is_err());
+        assert!(abi(Version(3, 0, 0)).is_err());
+    }
+}
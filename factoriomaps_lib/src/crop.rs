@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+
+use crate::render::BoundingBox;
+
+/// Surfaces and parts of surfaces that are rendered
+///
+/// Applied to the chunks picked by pruning before the mod takes screenshots and again when
+/// the tile set is built, so both always agree.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CropConfig {
+    /// Surface name patterns to render, all surfaces if empty
+    pub include: Vec<String>,
+    /// Surface name patterns to skip, wins over `include`
+    pub exclude: Vec<String>,
+    /// Area of every surface to render, all of it if `None`
+    pub area: Option<CropArea>,
+}
+impl CropConfig {
+    pub fn includes_surface(&self, surface: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| wildcard_match(p, surface)))
+            && !self.exclude.iter().any(|p| wildcard_match(p, surface))
+    }
+    /// Whether the chunk at `x`, `y` overlaps the area, `tags` are the position and text of the
+    /// chart tags on its surface
+    pub fn includes_chunk(&self, x: i32, y: i32, tags: &[([f64; 2], &str)]) -> bool {
+        self.area
+            .as_ref()
+            .is_none_or(|area| area.overlaps_chunk(x, y, tags))
+    }
+}
+
+/// Area in game coordinates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CropArea {
+    BoundingBox(BoundingBox),
+    /// Corners in order, the last one connects back to the first
+    Polygon(Vec<[f64; 2]>),
+    /// Everything within `radius` tiles of a chart tag whose text matches `pattern`
+    Tags {
+        pattern: String,
+        radius: f64,
+    },
+}
+impl CropArea {
+    fn overlaps_chunk(&self, x: i32, y: i32, tags: &[([f64; 2], &str)]) -> bool {
+        let (left, top) = (x as f64 * 32.0, y as f64 * 32.0);
+        let (right, bottom) = (left + 32.0, top + 32.0);
+        match self {
+            CropArea::BoundingBox(b) => {
+                b.left < right && left < b.right && b.top < bottom && top < b.bottom
+            }
+            CropArea::Polygon(points) => {
+                let in_chunk =
+                    |&[px, py]: &[f64; 2]| left <= px && px < right && top <= py && py < bottom;
+                let corners = [[left, top], [right, top], [right, bottom], [left, bottom]];
+                // chunk inside the polygon, polygon inside the chunk or the outlines crossing
+                point_in_polygon([left + 16.0, top + 16.0], points)
+                    || points.iter().any(in_chunk)
+                    || edges(points)
+                        .any(|(a, b)| edges(&corners).any(|(c, d)| segments_intersect(a, b, c, d)))
+            }
+            CropArea::Tags { pattern, radius } => tags.iter().any(|&([tx, ty], text)| {
+                let dx = tx - tx.clamp(left, right);
+                let dy = ty - ty.clamp(top, bottom);
+                wildcard_match(pattern, text) && dx * dx + dy * dy <= radius * radius
+            }),
+        }
+    }
+}
+
+fn edges(points: &[[f64; 2]]) -> impl Iterator<Item = ([f64; 2], [f64; 2])> + '_ {
+    points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(&a, &b)| (a, b))
+}
+
+/// Even-odd rule
+fn point_in_polygon([x, y]: [f64; 2], points: &[[f64; 2]]) -> bool {
+    edges(points)
+        .filter(|&([ax, ay], [bx, by])| {
+            (ay > y) != (by > y) && x < ax + (y - ay) * (bx - ax) / (by - ay)
+        })
+        .count()
+        % 2
+        == 1
+}
+
+fn segments_intersect(a: [f64; 2], b: [f64; 2], c: [f64; 2], d: [f64; 2]) -> bool {
+    let cross = |o: [f64; 2], p: [f64; 2], q: [f64; 2]| {
+        (p[0] - o[0]) * (q[1] - o[1]) - (p[1] - o[1]) * (q[0] - o[0])
+    };
+    // only proper crossings, outlines merely touching don't overlap
+    cross(c, d, a) * cross(c, d, b) < 0.0 && cross(a, b, c) * cross(a, b, d) < 0.0
+}
+
+/// Matches `text` against `pattern` where `*` matches any run of characters and `?` any one
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position after the last `*` and the text position it currently matches up to
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((after_star, matched)) = star {
+            p = after_star;
+            t = matched + 1;
+            star = Some((after_star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("nauvis", "nauvis"));
+        assert!(!wildcard_match("nauvis", "nauvis2"));
+        assert!(wildcard_match("platform-*", "platform-1"));
+        assert!(wildcard_match("*base*", "main base east"));
+        assert!(wildcard_match("b?se", "base"));
+        assert!(!wildcard_match("*base", "bases"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn surface_filter() {
+        let crop = CropConfig {
+            include: vec!["nauvis".to_owned(), "platform-*".to_owned()],
+            exclude: vec!["platform-test".to_owned()],
+            area: None,
+        };
+        assert!(crop.includes_surface("nauvis"));
+        assert!(crop.includes_surface("platform-1"));
+        assert!(!crop.includes_surface("platform-test"));
+        assert!(!crop.includes_surface("vulcanus"));
+        assert!(CropConfig::default().includes_surface("vulcanus"));
+    }
+
+    #[test]
+    fn bounding_box() {
+        let area = CropArea::BoundingBox(BoundingBox {
+            left: -10.0,
+            top: -10.0,
+            right: 40.0,
+            bottom: 10.0,
+        });
+        assert!(area.overlaps_chunk(-1, -1, &[]));
+        assert!(area.overlaps_chunk(1, 0, &[]));
+        assert!(!area.overlaps_chunk(2, 0, &[]));
+        assert!(!area.overlaps_chunk(0, 1, &[]));
+    }
+
+    #[test]
+    fn polygon() {
+        // triangle covering chunk 0,0 and reaching into chunk 2,0 with its tip
+        let area = CropArea::Polygon(vec![[0.0, 0.0], [70.0, 10.0], [0.0, 40.0]]);
+        assert!(area.overlaps_chunk(0, 0, &[]));
+        assert!(area.overlaps_chunk(2, 0, &[]));
+        assert!(area.overlaps_chunk(0, 1, &[]));
+        assert!(!area.overlaps_chunk(2, 1, &[]));
+        assert!(!area.overlaps_chunk(-1, 0, &[]));
+
+        // chunk entirely inside a large square
+        let area = CropArea::Polygon(vec![
+            [-500.0, -500.0],
+            [500.0, -500.0],
+            [500.0, 500.0],
+            [-500.0, 500.0],
+        ]);
+        assert!(area.overlaps_chunk(3, -3, &[]));
+    }
+
+    #[test]
+    fn tags() {
+        let area = CropArea::Tags {
+            pattern: "*base*".to_owned(),
+            radius: 40.0,
+        };
+        let tags = [([16.0, 16.0], "main base"), ([1000.0, 0.0], "outpost")];
+        assert!(area.overlaps_chunk(0, 0, &tags));
+        assert!(area.overlaps_chunk(1, 1, &tags));
+        assert!(!area.overlaps_chunk(3, 0, &tags));
+        assert!(!area.overlaps_chunk(31, 0, &tags));
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use image::{ImageEncoder, RgbaImage};
+
+use crate::render::TileFormat;
+
+/// Turns a finished RGBA tile part into the bytes written to disk
+///
+/// Implement this to add an output format without touching the worker threads and pass it
+/// to `render::spawn_threads` and `render::main_loop`.
+pub trait TileEncoder: Send + Sync {
+    /// File extension of encoded parts, without the leading dot
+    fn extension(&self) -> &'static str;
+    /// Whether the alpha channel survives encoding
+    fn supports_alpha(&self) -> bool {
+        false
+    }
+    fn encode(&self, part: &RgbaImage) -> Vec<u8>;
+}
+
+pub struct JpegEncoder {
+    pub quality: u8,
+}
+impl TileEncoder for JpegEncoder {
+    fn extension(&self) -> &'static str {
+        "jpg"
+    }
+    fn encode(&self, part: &RgbaImage) -> Vec<u8> {
+        let mut data = vec![];
+        let cur = std::io::Cursor::new(&mut data);
+        let encoder = jpeg_encoder::Encoder::new(cur, self.quality);
+        encoder
+            .encode(
+                part.as_raw(),
+                part.width() as u16,
+                part.height() as u16,
+                jpeg_encoder::ColorType::Rgba,
+            )
+            .unwrap();
+        data
+    }
+}
+
+pub struct WebpEncoder {
+    /// `None` for lossless
+    pub quality: Option<u8>,
+}
+impl TileEncoder for WebpEncoder {
+    fn extension(&self) -> &'static str {
+        "webp"
+    }
+    fn supports_alpha(&self) -> bool {
+        true
+    }
+    fn encode(&self, part: &RgbaImage) -> Vec<u8> {
+        let encoder = webp::Encoder::from_rgba(part.as_raw(), part.width(), part.height());
+        match self.quality {
+            Some(quality) => encoder.encode(quality as f32),
+            None => encoder.encode_lossless(),
+        }
+        .to_vec()
+    }
+}
+
+pub struct PngEncoder;
+impl TileEncoder for PngEncoder {
+    fn extension(&self) -> &'static str {
+        "png"
+    }
+    fn supports_alpha(&self) -> bool {
+        true
+    }
+    fn encode(&self, part: &RgbaImage) -> Vec<u8> {
+        let mut data = vec![];
+        image::codecs::png::PngEncoder::new(&mut data)
+            .write_image(
+                part.as_raw(),
+                part.width(),
+                part.height(),
+                image::ColorType::Rgba8,
+            )
+            .unwrap();
+        data
+    }
+}
+
+pub struct QoiEncoder;
+impl TileEncoder for QoiEncoder {
+    fn extension(&self) -> &'static str {
+        "qoi"
+    }
+    fn supports_alpha(&self) -> bool {
+        true
+    }
+    fn encode(&self, part: &RgbaImage) -> Vec<u8> {
+        let mut data = vec![];
+        image::codecs::qoi::QoiEncoder::new(&mut data)
+            .write_image(
+                part.as_raw(),
+                part.width(),
+                part.height(),
+                image::ColorType::Rgba8,
+            )
+            .unwrap();
+        data
+    }
+}
+
+impl TileFormat {
+    /// Returns the built-in encoder for this format
+    pub fn encoder(&self, quality: u8) -> Arc<dyn TileEncoder> {
+        match self {
+            TileFormat::Jpeg => Arc::new(JpegEncoder { quality }),
+            TileFormat::Webp => Arc::new(WebpEncoder {
+                quality: Some(quality),
+            }),
+            TileFormat::WebpLossless => Arc::new(WebpEncoder { quality: None }),
+            TileFormat::Png => Arc::new(PngEncoder),
+            TileFormat::Qoi => Arc::new(QoiEncoder),
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use image::{DynamicImage, GenericImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use crate::render::{self, RenderConfig};
+use crate::sink::OutputSink;
+
+/// Additional static layouts of the finished pyramid for viewers other than the bundled one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportKind {
+    /// Deep Zoom descriptor and level folders under `dzi/`
+    Dzi,
+    /// IIIF Image API 3 level 0 tiles and `info.json` under `iiif/`
+    Iiif,
+}
+impl std::str::FromStr for ExportKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dzi" => Ok(ExportKind::Dzi),
+            "iiif" => Ok(ExportKind::Iiif),
+            _ => Err(format!(
+                "unknown export \"{s}\", expected one of: dzi, iiif"
+            )),
+        }
+    }
+}
+
+/// Checks the options the exports of `config` need, before anything is rendered
+pub fn validate(config: &RenderConfig) -> Result<(), String> {
+    if config.exports.contains(&ExportKind::Iiif) {
+        base_url(config)?;
+    }
+    Ok(())
+}
+
+/// IIIF image ids have to be absolute URIs
+fn base_url(config: &RenderConfig) -> Result<&str, String> {
+    match config.iiif_base_url.as_deref() {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => Ok(url),
+        Some(url) => Err(format!(
+            "has to be an absolute http:// or https:// URL, got \"{url}\""
+        )),
+        None => Err("required by the iiif export".to_owned()),
+    }
+}
+
+/// Area of a surface covered by the export, in parts at max zoom
+///
+/// The origin and size are aligned to the parts of the lowest zoom level so that every level
+/// is made of whole parts and no part has to be cropped.
+struct Extent {
+    origin_x: i32,
+    origin_y: i32,
+    width: u64,
+    height: u64,
+    min_zoom: i32,
+}
+impl Extent {
+    fn new(config: &RenderConfig, parts: &[(i32, i32, i32)]) -> Option<Extent> {
+        let min_zoom = parts.iter().map(|p| p.0).min()?;
+        let cell = 1 << (config.max_zoom - min_zoom);
+        let full = parts.iter().filter(|p| p.0 == config.max_zoom);
+        let min_x = full.clone().map(|p| p.1).min()?;
+        let min_y = full.clone().map(|p| p.2).min()?;
+        let max_x = full.clone().map(|p| p.1).max()?;
+        let max_y = full.map(|p| p.2).max()?;
+
+        let origin_x = min_x.div_floor(cell) * cell;
+        let origin_y = min_y.div_floor(cell) * cell;
+        let part_size = config.part_size() as u64;
+        Some(Extent {
+            origin_x,
+            origin_y,
+            width: (max_x + 1 - origin_x).div_ceil(cell) as u64 * cell as u64 * part_size,
+            height: (max_y + 1 - origin_y).div_ceil(cell) as u64 * cell as u64 * part_size,
+            min_zoom,
+        })
+    }
+    /// Column and row of a part within its zoom level
+    fn position(&self, config: &RenderConfig, zoom: i32, x: i32, y: i32) -> (i32, i32) {
+        let scale = 1 << (config.max_zoom - zoom);
+        (x - self.origin_x / scale, y - self.origin_y / scale)
+    }
+}
+
+/// Writes `kind` for one surface from the parts already stored in `sink`
+pub fn export(
+    kind: ExportKind,
+    sink: &dyn OutputSink,
+    config: &RenderConfig,
+    extension: &str,
+    surface: &str,
+    parts: &[(i32, i32, i32)],
+) {
+    let Some(extent) = Extent::new(config, parts) else {
+        return;
+    };
+    match kind {
+        ExportKind::Dzi => export_dzi(sink, config, extension, surface, parts, &extent),
+        ExportKind::Iiif => export_iiif(sink, config, extension, surface, parts, &extent),
+    }
+}
+
+fn export_dzi(
+    sink: &dyn OutputSink,
+    config: &RenderConfig,
+    extension: &str,
+    surface: &str,
+    parts: &[(i32, i32, i32)],
+    extent: &Extent,
+) {
+    let part_size = config.part_size();
+    let descriptor = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Image xmlns="http://schemas.microsoft.com/deepzoom/2008" Format="{extension}" Overlap="0" TileSize="{part_size}">
+  <Size Width="{}" Height="{}"/>
+</Image>
+"#,
+        extent.width, extent.height
+    );
+    sink.write_file(
+        Path::new(&format!("dzi/{surface}.dzi")),
+        descriptor.as_bytes(),
+    );
+
+    // DZI levels go down to a single pixel, max zoom is the last one
+    let max_level = extent.width.max(extent.height).next_power_of_two().ilog2() as i32;
+    let path = |level: i32, col: i32, row: i32| {
+        format!("dzi/{surface}_files/{level}/{col}_{row}.{extension}")
+    };
+    for &(zoom, x, y) in parts {
+        let level = max_level - (config.max_zoom - zoom);
+        let (col, row) = extent.position(config, zoom, x, y);
+        sink.export_part(surface, zoom, x, y, Path::new(&path(level, col, row)));
+    }
+
+    // viewers request every level, the ones below the lowest zoom level are downscaled from it
+    let shift = config.max_zoom - extent.min_zoom;
+    let mut level = max_level - shift;
+    let (mut width, mut height) = (extent.width >> shift, extent.height >> shift);
+    let mut level_parts: HashMap<(i32, i32), DynamicImage> = parts
+        .iter()
+        .filter(|p| p.0 == extent.min_zoom)
+        .filter_map(|&(zoom, x, y)| {
+            let data = sink.read_part(surface, zoom, x, y)?;
+            let image = image::load_from_memory(&data).ok()?;
+            Some((extent.position(config, zoom, x, y), image))
+        })
+        .collect();
+    let part_size = part_size as u64;
+    let encoder = config.encoder();
+    while level > 0 {
+        level -= 1;
+        width = width.div_ceil(2);
+        height = height.div_ceil(2);
+        let mut next = HashMap::new();
+        for row in 0..height.div_ceil(part_size) as i32 {
+            for col in 0..width.div_ceil(part_size) as i32 {
+                let size = part_size as u32;
+                let mut children = DynamicImage::new_rgba8(size * 2, size * 2);
+                let mut empty = true;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let Some(child) = level_parts.get(&(col * 2 + dx, row * 2 + dy)) else {
+                        continue;
+                    };
+                    children
+                        .copy_from(child, dx as u32 * size, dy as u32 * size)
+                        .unwrap();
+                    empty = false;
+                }
+                if empty {
+                    continue;
+                }
+                // the next level is built from the unflattened image so the matte doesn't bleed
+                let image = render::image_resize(children, size, config);
+                // parts on the right and bottom edge are cut to the size of the level
+                let mut part = image
+                    .view(
+                        0,
+                        0,
+                        (width - col as u64 * part_size).min(part_size) as u32,
+                        (height - row as u64 * part_size).min(part_size) as u32,
+                    )
+                    .to_image();
+                if !config.transparent {
+                    render::flatten_alpha(&mut part, config.matte);
+                }
+                sink.write_file(Path::new(&path(level, col, row)), &encoder.encode(&part));
+                next.insert((col, row), image);
+            }
+        }
+        level_parts = next;
+    }
+}
+
+fn export_iiif(
+    sink: &dyn OutputSink,
+    config: &RenderConfig,
+    extension: &str,
+    surface: &str,
+    parts: &[(i32, i32, i32)],
+    extent: &Extent,
+) {
+    let part_size = config.part_size();
+    let scale_factors: Vec<u64> = (0..=config.max_zoom - extent.min_zoom)
+        .map(|n| 1 << n)
+        .collect();
+    let info = serde_json::json!({
+        "@context": "http://iiif.io/api/image/3/context.json",
+        "id": format!("{}/{surface}", base_url(config).unwrap().trim_end_matches('/')),
+        "type": "ImageService3",
+        "protocol": "http://iiif.io/api/image",
+        "profile": "level0",
+        "width": extent.width,
+        "height": extent.height,
+        "tiles": [{
+            "width": part_size,
+            "height": part_size,
+            "scaleFactors": scale_factors,
+        }],
+        "preferredFormats": [extension],
+    });
+    sink.write_file(
+        Path::new(&format!("iiif/{surface}/info.json")),
+        &serde_json::to_vec(&info).unwrap(),
+    );
+
+    for &(zoom, x, y) in parts {
+        let region = part_size as i64 * (1 << (config.max_zoom - zoom));
+        let (col, row) = extent.position(config, zoom, x, y);
+        let (region_x, region_y) = (col as i64 * region, row as i64 * region);
+        sink.export_part(
+            surface,
+            zoom,
+            x,
+            y,
+            Path::new(&format!(
+                "iiif/{surface}/{region_x},{region_y},{region},{region}/{part_size},{part_size}/0/default.{extension}"
+            )),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::render::TileFormat;
+    use crate::sink::TileLayout;
+
+    /// Keeps parts and files in memory
+    #[derive(Default)]
+    struct MemorySink {
+        parts: Mutex<HashMap<(i32, i32, i32), Vec<u8>>>,
+        files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    }
+    impl OutputSink for MemorySink {
+        fn write_part(&self, _surface: &str, zoom: i32, x: i32, y: i32, data: Vec<u8>) {
+            self.parts.lock().unwrap().insert((zoom, x, y), data);
+        }
+        fn read_part(&self, _surface: &str, zoom: i32, x: i32, y: i32) -> Option<Vec<u8>> {
+            self.parts.lock().unwrap().get(&(zoom, x, y)).cloned()
+        }
+        fn write_file(&self, path: &Path, data: &[u8]) {
+            self.files
+                .lock()
+                .unwrap()
+                .insert(path.to_owned(), data.to_vec());
+        }
+        fn finish(&self) -> TileLayout {
+            unreachable!()
+        }
+    }
+
+    /// Two chunks side by side at zoom 20 with 4 pixel parts, zoomed out to 19. Parts at zoom
+    /// 20 are blue and at zoom 19 red
+    fn render() -> (RenderConfig, MemorySink, Vec<(i32, i32, i32)>) {
+        let config = RenderConfig {
+            tile_size: 8,
+            format: TileFormat::Png,
+            iiif_base_url: Some("https://example.com/iiif/".to_owned()),
+            ..Default::default()
+        };
+        let sink = MemorySink::default();
+        let mut parts = vec![];
+        for (zoom, xs, colour) in [(20, 0..4, [0, 0, 255, 255]), (19, 0..2, [255, 0, 0, 255])] {
+            for x in xs {
+                for y in 0..2 {
+                    let part = image::RgbaImage::from_pixel(4, 4, image::Rgba(colour));
+                    sink.write_part("nauvis", zoom, x, y, config.encoder().encode(&part));
+                    parts.push((zoom, x, y));
+                }
+            }
+        }
+        (config, sink, parts)
+    }
+
+    fn file(sink: &MemorySink, path: &str) -> Vec<u8> {
+        let files = sink.files.lock().unwrap();
+        files
+            .get(Path::new(path))
+            .unwrap_or_else(|| panic!("{path} not written"))
+            .clone()
+    }
+
+    #[test]
+    fn dzi() {
+        let (config, sink, parts) = render();
+        export(ExportKind::Dzi, &sink, &config, "png", "nauvis", &parts);
+
+        let descriptor = String::from_utf8(file(&sink, "dzi/nauvis.dzi")).unwrap();
+        assert!(descriptor.contains(r#"Format="png" Overlap="0" TileSize="4""#));
+        assert!(descriptor.contains(r#"<Size Width="16" Height="8"/>"#));
+
+        // 16x8 pixels are 4 levels above the single pixel of level 0
+        let part = sink.read_part("nauvis", 20, 2, 1).unwrap();
+        assert_eq!(file(&sink, "dzi/nauvis_files/4/2_1.png"), part);
+        let part = sink.read_part("nauvis", 19, 1, 0).unwrap();
+        assert_eq!(file(&sink, "dzi/nauvis_files/3/1_0.png"), part);
+
+        // levels below zoom 19 are downscaled and cut to the size of the level
+        for (path, width, height) in [
+            ("dzi/nauvis_files/2/0_0.png", 4, 2),
+            ("dzi/nauvis_files/1/0_0.png", 2, 1),
+            ("dzi/nauvis_files/0/0_0.png", 1, 1),
+        ] {
+            let image = image::load_from_memory(&file(&sink, path))
+                .unwrap()
+                .into_rgba8();
+            assert_eq!(image.dimensions(), (width, height), "{path}");
+            if width > 1 {
+                let p = image.get_pixel(0, 0);
+                assert!(p[0] >= 250 && p[1] <= 5 && p[2] <= 5, "{path} is {p:?}");
+            }
+        }
+        assert!(!sink
+            .files
+            .lock()
+            .unwrap()
+            .contains_key(Path::new("dzi/nauvis_files/2/1_0.png")));
+    }
+
+    #[test]
+    fn iiif() {
+        let (config, sink, parts) = render();
+        validate(&config).unwrap();
+        export(ExportKind::Iiif, &sink, &config, "png", "nauvis", &parts);
+
+        let info: serde_json::Value =
+            serde_json::from_slice(&file(&sink, "iiif/nauvis/info.json")).unwrap();
+        assert_eq!(info["id"], "https://example.com/iiif/nauvis");
+        assert_eq!(info["profile"], "level0");
+        assert_eq!(
+            (info["width"].as_u64(), info["height"].as_u64()),
+            (Some(16), Some(8))
+        );
+        assert_eq!(
+            info["tiles"],
+            serde_json::json!([{"width": 4, "height": 4, "scaleFactors": [1, 2]}])
+        );
+
+        // regions are in full resolution pixels, every tile is scaled to the part size
+        let part = sink.read_part("nauvis", 20, 3, 1).unwrap();
+        assert_eq!(file(&sink, "iiif/nauvis/12,4,4,4/4,4/0/default.png"), part);
+        let part = sink.read_part("nauvis", 19, 1, 0).unwrap();
+        assert_eq!(file(&sink, "iiif/nauvis/8,0,8,8/4,4/0/default.png"), part);
+    }
+
+    #[test]
+    fn iiif_base_url() {
+        let mut config = RenderConfig {
+            exports: vec![ExportKind::Iiif],
+            ..Default::default()
+        };
+        assert!(validate(&config).is_err());
+        config.iiif_base_url = Some("iiif".to_owned());
+        assert!(validate(&config).is_err());
+        config.iiif_base_url = Some("http://localhost/iiif".to_owned());
+        assert!(validate(&config).is_ok());
+    }
+}
@@ -1,14 +1,24 @@
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
 use std::ffi::{CStr, OsStr};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
-use crate::render::{self, MessageToMain, MessageToWorker, VirtualFile};
+use crate::compat::{self, Abi, SaveToFile};
+use crate::render::{self, MessageToMain, MessageToWorker, RenderConfig, VirtualFile};
 
 lazy_static::lazy_static! {
+    static ref CONFIG: RenderConfig = serde_json::from_str(&std::env::var("FBRS_CONFIG").unwrap()).unwrap();
+    // detected by the CLI, the binary may be anywhere for package installs
+    static ref VERSION: compat::Version = std::env::var("FBRS_FACTORIO_VERSION").unwrap().parse().unwrap_or_else(|e| panic!("{e}"));
+    static ref ABI: &'static Abi = compat::abi(*VERSION).unwrap_or_else(|e| panic!("{e}"));
     static ref OPEN_FILES: Arc<Mutex<std::collections::HashMap<usize, Box<VirtualFile>>>> = Default::default();
 
+    // results stay unbounded so workers never wait on the main loop, which would deadlock
+    // while it waits on a full work queue
     static ref SR_RESULT: (Sender<MessageToMain>, Receiver<MessageToMain>) = unbounded::<MessageToMain>();
-    static ref SR_WORK: (Sender<MessageToWorker>, Receiver<MessageToWorker>) = unbounded::<MessageToWorker>();
+    // bounded so save_image blocks Factorio's screenshot writer when the workers fall behind
+    static ref SR_WORK: (Sender<MessageToWorker>, Receiver<MessageToWorker>) = bounded::<MessageToWorker>(CONFIG.queue_depth);
+    static ref SR_RESIZE: (Sender<MessageToWorker>, Receiver<MessageToWorker>) = bounded::<MessageToWorker>(CONFIG.queue_depth);
 }
 
 hooky::define_hook! {
@@ -16,7 +26,8 @@ hooky::define_hook! {
         let filename = unsafe { CStr::from_ptr(c_filename) }.to_str().unwrap();
         let mode = unsafe { CStr::from_ptr(c_mode) }.to_str().unwrap();
         let path = std::path::Path::new(filename);
-        if  mode.contains('w') && (path.file_name() == Some(OsStr::new("info.json")) || path.extension() == Some(OsStr::new("bmp"))) {
+        let intercepted = [OsStr::new("info.json"), OsStr::new(crate::prune::FACTS_FILE)];
+        if  mode.contains('w') && (path.file_name().is_some_and(|name| intercepted.contains(&name)) || path.extension() == Some(OsStr::new("bmp"))) {
             let file = Box::new(VirtualFile::new(filename));
             let ptr = (&*file as *const VirtualFile) as *mut libc::FILE;
             OPEN_FILES.lock().unwrap().insert(ptr as usize, file);
@@ -41,9 +52,31 @@ hooky::define_hook! {
     }
     unsafe fn fclose(file: *mut libc::FILE) -> libc::c_int {
         if let Some(vfile) = OPEN_FILES.lock().unwrap().remove(&(file as usize)) {
+            if vfile.path.file_name() == Some(OsStr::new(crate::prune::FACTS_FILE)) {
+                // the survey run only reports chunk facts for the CLI to prune, see prune.rs
+                let output = std::path::PathBuf::from(std::env::var("FBRS_OUTPUT").unwrap());
+                std::fs::create_dir_all(&output).unwrap();
+                std::fs::write(output.join(crate::prune::FACTS_FILE), &vfile.data).unwrap();
+                unsafe {
+                    libc::kill(std::process::id() as i32, libc::SIGTERM);
+                }
+                return 0;
+            }
             if vfile.path.file_name() == Some(OsStr::new("info.json")) {
                 main();
             }
+            if vfile.path.extension() == Some(OsStr::new("bmp")) {
+                // only reached without the saveToFile detour, which skips encoding the bitmap.
+                // BMPs without an alpha mask decode as opaque
+                match image::load_from_memory_with_format(&vfile.data, image::ImageFormat::Bmp) {
+                    Ok(image) => queue_screenshot(&vfile.path, image::DynamicImage::ImageRgba8(image.into_rgba8())),
+                    // a panic can't unwind out of the hook and would abort Factorio
+                    Err(e) => SR_RESULT.0
+                        .send(MessageToMain::Failed(format!("could not decode {}: {e}", vfile.path.display())))
+                        .unwrap(),
+                }
+                return 0;
+            }
             SR_RESULT.0
                 .send(MessageToMain::File(*vfile))
                 .unwrap();
@@ -53,35 +86,68 @@ hooky::define_hook! {
     }
 }
 
-#[repr(C)]
-struct CxxString {
-    data: *const u8,
-    length: usize,
-}
-impl CxxString {
-    fn to_str(&self) -> &str {
-        std::str::from_utf8(unsafe { std::slice::from_raw_parts(self.data, self.length) }).unwrap()
-    }
-}
-#[repr(C)]
-struct MemoryBitmap {
-    width: u32,
-    height: u32,
-    data: *const u8,
-    data_capacity: usize,
-    data_size: usize,
-    flipped: bool,
+/// Reads a `T` at `offset` bytes into `base`
+unsafe fn field<T: Copy>(base: *const u8, offset: usize) -> T {
+    unsafe { std::ptr::read_unaligned(base.add(offset) as *const T) }
 }
 
+/// Trampoline to the original MemoryBitmap::saveToFile
+static SAVE_TO_FILE: AtomicUsize = AtomicUsize::new(0);
+/// Whether the first bitmap matched the layout of the detour
+static LAYOUT_MATCHES: OnceLock<bool> = OnceLock::new();
+
 #[no_mangle]
-extern "C" fn save_image(bitmap: *const MemoryBitmap, path: *const CxxString, _quality: u8) {
-    let path = std::path::Path::new(unsafe { (*path).to_str() });
+extern "C" fn save_image(bitmap: *const u8, path: *const u8, quality: u8) {
+    let SaveToFile { bitmap: layout, path: path_layout, .. } = ABI.save_to_file.as_ref().unwrap();
 
-    // makes several assumptions about the layout of the bitmap
-    let MemoryBitmap { width, height, data, data_size, .. } = unsafe { &*bitmap };
+    let (width, height, data, data_size, flipped) = unsafe {
+        (
+            field::<u32>(bitmap, layout.width),
+            field::<u32>(bitmap, layout.height),
+            field::<*const u8>(bitmap, layout.data),
+            field::<usize>(bitmap, layout.data_size),
+            field::<u8>(bitmap, layout.flipped),
+        )
+    };
+    // a layout that doesn't match would read past the pixels, stop before touching them
+    let valid = width == CONFIG.tile_size
+        && height == CONFIG.tile_size
+        && data_size == width as usize * height as usize * 4
+        && flipped <= 1;
+    let layout_matches = *LAYOUT_MATCHES.get_or_init(|| {
+        if !valid {
+            println!("MemoryBitmap of Factorio {} doesn't match the known layout ({width}x{height}, {data_size} bytes, flipped {flipped}), decoding the BMP files it writes instead", *VERSION);
+        }
+        valid
+    });
+    if !layout_matches {
+        // Factorio writes the BMP itself, which the fopen hook intercepts
+        let save_to_file: extern "C" fn(*const u8, *const u8, u8) =
+            unsafe { std::mem::transmute(SAVE_TO_FILE.load(Ordering::Acquire)) };
+        save_to_file(bitmap, path, quality);
+        return;
+    }
+    assert!(valid, "MemoryBitmap of Factorio {} changed layout after the first screenshot", *VERSION);
 
-    let data = unsafe { std::slice::from_raw_parts(*data, *data_size) }.to_vec();
+    let path = unsafe {
+        let data = field::<*const u8>(path, path_layout.data);
+        let length = field::<usize>(path, path_layout.length);
+        assert!(length < 4096, "Filesystem::Path of Factorio {} doesn't match the known layout", *VERSION);
+        std::str::from_utf8(std::slice::from_raw_parts(data, length)).unwrap()
+    };
 
+    let data = unsafe { std::slice::from_raw_parts(data, data_size) }.to_vec();
+    let mut image = image::DynamicImage::ImageRgba8(
+        image::RgbaImage::from_raw(width, height, data).unwrap(),
+    );
+    if flipped == 1 {
+        image = image.flipv();
+    }
+    queue_screenshot(std::path::Path::new(path), image);
+}
+
+/// Queues the screenshot of a chunk at `path` for writing, blocks while the workers are behind
+fn queue_screenshot(path: &std::path::Path, image: image::DynamicImage) {
     let mut split = path
         .file_stem()
         .and_then(std::ffi::OsStr::to_str)
@@ -90,39 +156,67 @@ extern "C" fn save_image(bitmap: *const MemoryBitmap, path: *const CxxString, _q
     let surface = split.next().unwrap().to_owned();
     let x = split.next().unwrap().parse::<i32>().unwrap();
     let y = split.next().unwrap().parse::<i32>().unwrap();
+    // screenshots of render variants end in the variant name, see `render::split_variants`
+    let surface = match split.next() {
+        Some(variant) => format!("{surface}/{variant}"),
+        None => surface,
+    };
 
-    let tile = crate::render::Tile::new_max_zoom(surface, x, y);
-    let image = image::DynamicImage::ImageRgba8(
-        image::RgbaImage::from_raw(*width, *height, data).unwrap(),
-    );
+    let tile = crate::render::Tile::new_max_zoom(&CONFIG, surface, x, y);
     SR_WORK.0
         .send(MessageToWorker::TileWriteParts { tile, image })
         .unwrap();
 }
 
-fn main() {
+/// Replaces MemoryBitmap::saveToFile so screenshots skip BMP encoding, returns false if this
+/// version has no known detour or the symbol isn't exported by this build
+fn install_detour() -> bool {
+    use udbg::prelude::UDbgEngine;
+    let Some(save_to_file) = &ABI.save_to_file else {
+        return false;
+    };
+    let mut engine = udbg::os::DefaultEngine::default();
+    let target = engine.open_self().unwrap();
+    let factorio = target.enum_module().unwrap().find(|m| m.data().name.starts_with("factorio")).unwrap();
+
+    let Some(sym) = factorio.get_symbol(save_to_file.symbol) else {
+        return false;
+    };
+    let mut address = sym.offset as usize;
+    if &*factorio.data().name != "factorio" {
+        address += factorio.data().base; // in package build symbol is relative to the module
+    }
     unsafe {
-        use udbg::prelude::UDbgEngine;
-        let mut engine = udbg::os::DefaultEngine::default();
-        let target = engine.open_self().unwrap();
-        let factorio = target.enum_module().unwrap().find(|m| m.data().name.starts_with("factorio")).unwrap();
-
-        let sym = factorio.get_symbol("_ZNK12MemoryBitmap10saveToFileERKN10Filesystem4PathEh").unwrap();
-        let mut address = sym.offset as usize;
-        if &*factorio.data().name != "factorio" {
-            address += factorio.data().base; // in package build symbol is relative to the module
-        }
-        retour::RawDetour::new(address as *const (), save_image as *const ()).unwrap().enable().unwrap();
+        let detour = retour::RawDetour::new(address as *const (), save_image as *const ()).unwrap();
+        SAVE_TO_FILE.store(detour.trampoline() as *const () as usize, Ordering::Release);
+        detour.enable().unwrap();
+        // stays enabled for the lifetime of Factorio
+        std::mem::forget(detour);
+    }
+    true
+}
+
+fn main() {
+    if install_detour() {
+        println!("Factorio {}: capturing screenshots with the MemoryBitmap::saveToFile detour", *VERSION);
+    } else {
+        println!("Factorio {}: no MemoryBitmap::saveToFile detour, decoding the BMP files it writes instead", *VERSION);
     }
 
     let output = std::env::var("FBRS_OUTPUT").unwrap();
 
-    let (result_rx, work_tx, result_tx) =
-        (SR_RESULT.1.clone(), SR_WORK.0.clone(), SR_RESULT.0.clone());
+    let (result_rx, work_tx, resize_tx, result_tx) = (
+        SR_RESULT.1.clone(),
+        SR_WORK.0.clone(),
+        SR_RESIZE.0.clone(),
+        SR_RESULT.0.clone(),
+    );
     std::thread::spawn(move || {
+        let encoder = CONFIG.encoder();
+        let sink = CONFIG.sink.sink(&output, &CONFIG, encoder.extension());
         let res = crossbeam::scope(|scope| {
-            render::spawn_threads(&output, scope, SR_WORK.1.clone(), SR_RESULT.0.clone());
-            render::main_loop(output, result_rx, work_tx, result_tx);
+            render::spawn_threads(sink.clone(), &CONFIG, encoder.clone(), scope, SR_WORK.1.clone(), SR_RESIZE.1.clone(), SR_RESULT.0.clone());
+            render::main_loop(output, &CONFIG, &*encoder, &*sink, result_rx, work_tx, resize_tx, result_tx);
             unsafe {
                 libc::kill(std::process::id() as i32, libc::SIGTERM);
             }
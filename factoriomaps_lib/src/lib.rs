@@ -1,4 +1,13 @@
 #![feature(int_roundings)]
 
+pub mod compat;
+pub mod crop;
+pub mod encoder;
+pub mod export;
 pub mod ldpreload;
+pub mod overlay;
+pub mod poster;
+pub mod prune;
 pub mod render;
+pub mod replay;
+pub mod sink;
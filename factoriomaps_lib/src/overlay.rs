@@ -0,0 +1,98 @@
+use std::path::Path;
+
+use image::RgbaImage;
+use serde::Serialize;
+
+use crate::encoder::{PngEncoder, TileEncoder};
+use crate::prune::{self, ChunkClass, SurfaceFacts};
+use crate::render::RenderConfig;
+use crate::sink::OutputSink;
+
+/// Pixels per chunk the overlay is drawn at, the viewer scales it to every other zoom level
+const CHUNK_PIXELS: u32 = 8;
+
+/// Translucent layer colouring every chunk by its pruning classification
+///
+/// Parts are stored at `debug-pruning/<surface>/<zoom>/<x>/<y>.png` for a single zoom level
+/// independent of the tile sink.
+#[derive(Debug, Clone, Serialize)]
+pub struct PruningOverlay {
+    pub zoom: i32,
+    pub parts: Vec<(i32, i32)>,
+}
+
+fn colour(class: ChunkClass) -> [u8; 4] {
+    // neighbouring areas are told apart by cycling through a few shades
+    const ISLANDS: [[u8; 3]; 4] = [
+        [0, 116, 217],
+        [127, 219, 255],
+        [177, 13, 201],
+        [57, 204, 204],
+    ];
+    const PRUNED: [[u8; 3]; 4] = [[255, 65, 54], [255, 133, 27], [133, 20, 75], [240, 18, 190]];
+    let ([r, g, b], a) = match class {
+        ChunkClass::Seed => ([46, 204, 64], 140),
+        ChunkClass::Margin => ([255, 220, 0], 110),
+        ChunkClass::Island { area } => (ISLANDS[area as usize % ISLANDS.len()], 120),
+        ChunkClass::Pruned { area } => (PRUNED[area as usize % PRUNED.len()], 90),
+    };
+    [r, g, b, a]
+}
+
+/// Classifies the chunks of `facts` like the render did and writes the overlay through `sink`
+pub fn write_pruning_overlay(
+    sink: &dyn OutputSink,
+    config: &RenderConfig,
+    facts: &SurfaceFacts,
+) -> Option<PruningOverlay> {
+    let classes = prune::classify(&config.prune, &facts.chunks);
+    let min_x = facts.chunks.iter().map(|c| c.x).min()? as i64;
+    let min_y = facts.chunks.iter().map(|c| c.y).min()? as i64;
+    let max_x = facts.chunks.iter().map(|c| c.x).max()? as i64;
+    let max_y = facts.chunks.iter().map(|c| c.y).max()? as i64;
+
+    // a chunk is tile_size pixels at max zoom and halves with every zoom level below
+    let levels = (config.tile_size / CHUNK_PIXELS).max(1).ilog2();
+    let zoom = config.max_zoom - levels as i32;
+    let tile_size = config.tile_size as i64;
+    let scale = 1i64 << levels;
+    let part_size = config.part_size() as i64;
+    let chunk_at = |pixel: i64| (pixel * scale).div_floor(tile_size) as i32;
+
+    let first_part = |chunk: i64| (chunk * tile_size).div_floor(scale).div_floor(part_size);
+    let last_part =
+        |chunk: i64| (((chunk + 1) * tile_size).div_ceil(scale) - 1).div_floor(part_size);
+
+    let encoder = PngEncoder;
+    let mut parts = vec![];
+    for part_y in first_part(min_y)..=last_part(max_y) {
+        for part_x in first_part(min_x)..=last_part(max_x) {
+            let mut empty = true;
+            let image = RgbaImage::from_fn(part_size as u32, part_size as u32, |x, y| {
+                let chunk = (
+                    chunk_at(part_x * part_size + x as i64),
+                    chunk_at(part_y * part_size + y as i64),
+                );
+                match classes.get(&chunk) {
+                    Some(&class) => {
+                        empty = false;
+                        image::Rgba(colour(class))
+                    }
+                    None => image::Rgba([0; 4]),
+                }
+            });
+            if empty {
+                continue;
+            }
+            sink.write_file(
+                Path::new(&format!(
+                    "debug-pruning/{}/{zoom}/{part_x}/{part_y}.png",
+                    facts.name
+                )),
+                &encoder.encode(&image),
+            );
+            parts.push((part_x as i32, part_y as i32));
+        }
+    }
+    Some(PruningOverlay { zoom, parts })
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::render::{BoundingBox, Manifest, RenderConfig};
+use crate::sink::SurfaceGrid;
+
+/// Image file a poster is written as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PosterFormat {
+    Png,
+    Tiff,
+}
+impl PosterFormat {
+    /// Picks the format from the extension of `path`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "png" => Some(PosterFormat::Png),
+            "tif" | "tiff" => Some(PosterFormat::Tiff),
+            _ => None,
+        }
+    }
+}
+
+/// Calls its argument with every row of pixels of the poster from top to bottom
+type RowReader<'a> = dyn FnMut(&mut dyn FnMut(&[u8])) + 'a;
+
+/// Pixels covered by the poster at the chosen zoom level, ends exclusive
+struct PosterArea {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+}
+impl PosterArea {
+    fn new(
+        config: &RenderConfig,
+        grid: &SurfaceGrid,
+        zoom: i32,
+        bbox: Option<BoundingBox>,
+    ) -> Self {
+        // game tiles covered by one pixel, a chunk is 32 tiles and tile_size pixels at max zoom
+        let scale = 32.0 * 2f64.powi(config.max_zoom - zoom) / config.tile_size as f64;
+        let bbox = bbox.unwrap_or(BoundingBox {
+            left: grid.min_x as f64 * 32.0,
+            top: grid.min_y as f64 * 32.0,
+            right: (grid.max_x + 1) as f64 * 32.0,
+            bottom: (grid.max_y + 1) as f64 * 32.0,
+        });
+        PosterArea {
+            x0: (bbox.left / scale).floor() as i64,
+            y0: (bbox.top / scale).floor() as i64,
+            x1: (bbox.right / scale).ceil() as i64,
+            y1: (bbox.bottom / scale).ceil() as i64,
+        }
+    }
+    fn width(&self) -> u32 {
+        (self.x1 - self.x0).try_into().expect("poster is too wide")
+    }
+    fn height(&self) -> u32 {
+        (self.y1 - self.y0).try_into().expect("poster is too high")
+    }
+}
+
+/// Stitches every part of `surface` at `zoom` in the finished render in `output` into a single
+/// image at `path`
+///
+/// Parts are read one row at a time and written out before the next row is read, so memory
+/// use depends on the width of the poster only. Defaults to the max zoom level and the whole
+/// surface.
+pub fn poster(
+    output: &Path,
+    surface: &str,
+    zoom: Option<i32>,
+    bbox: Option<BoundingBox>,
+    path: &Path,
+) {
+    let format = PosterFormat::from_path(path).expect("poster must be a .png, .tif or .tiff file");
+    let manifest = Manifest::read(output).expect("no finished render found in output");
+    let config = manifest.config;
+    assert!(
+        config.sink.readable(),
+        "posters can't be made from --sink {:?}",
+        config.sink
+    );
+    let chunks = manifest
+        .surfaces
+        .get(surface)
+        .unwrap_or_else(|| {
+            let mut names: Vec<&String> = manifest.surfaces.keys().collect();
+            names.sort();
+            panic!("unknown surface \"{surface}\", expected one of: {names:?}")
+        })
+        .chunks
+        .keys()
+        .map(|key| {
+            let (x, y) = key.split_once(',').unwrap();
+            (x.parse().unwrap(), y.parse().unwrap())
+        });
+    let grid = SurfaceGrid::new(&config, chunks).expect("surface has no chunks");
+
+    let zoom = zoom.unwrap_or(config.max_zoom);
+    assert!(
+        (grid.min_zoom..=config.max_zoom).contains(&zoom),
+        "zoom must be between {} and {}",
+        grid.min_zoom,
+        config.max_zoom
+    );
+
+    let encoder = config.encoder();
+    let sink = config.sink.sink(output, &config, encoder.extension());
+    sink.begin(&HashMap::from([(surface.to_owned(), grid.clone())]));
+
+    let area = PosterArea::new(&config, &grid, zoom, bbox);
+    let channels = if config.transparent { 4 } else { 3 };
+    let part_size = config.part_size() as i64;
+    let width = area.width() as usize;
+
+    let progress = ProgressBar::new(area.height() as u64);
+    progress.set_style(
+        ProgressStyle::with_template("{wide_bar} {pos}/{len} rows, ETA: {eta}").unwrap(),
+    );
+
+    let [r, g, b] = config.matte.0;
+    let background = [r, g, b, 0];
+
+    let mut read_rows = |emit: &mut dyn FnMut(&[u8])| {
+        for part_y in area.y0.div_floor(part_size)..=(area.y1 - 1).div_floor(part_size) {
+            // one row of parts cropped to the poster width, missing parts are left as background
+            let mut strip: Vec<u8> = background[..channels]
+                .iter()
+                .copied()
+                .cycle()
+                .take(width * part_size as usize * channels)
+                .collect();
+
+            for part_x in area.x0.div_floor(part_size)..=(area.x1 - 1).div_floor(part_size) {
+                let Some(data) = sink.read_part(surface, zoom, part_x as i32, part_y as i32) else {
+                    continue;
+                };
+                let part = image::load_from_memory(&data).unwrap();
+                let part = if config.transparent {
+                    part.into_rgba8().into_raw()
+                } else {
+                    part.into_rgb8().into_raw()
+                };
+
+                let from = (part_x * part_size).max(area.x0);
+                let to = ((part_x + 1) * part_size).min(area.x1);
+                let src = (from - part_x * part_size) as usize * channels;
+                let dst = (from - area.x0) as usize * channels;
+                let len = (to - from) as usize * channels;
+                for row in 0..part_size as usize {
+                    let src = row * part_size as usize * channels + src;
+                    let dst = row * width * channels + dst;
+                    strip[dst..dst + len].copy_from_slice(&part[src..src + len]);
+                }
+            }
+
+            let from = (part_y * part_size).max(area.y0);
+            let to = ((part_y + 1) * part_size).min(area.y1);
+            for y in from..to {
+                let row = (y - part_y * part_size) as usize * width * channels;
+                emit(&strip[row..row + width * channels]);
+                progress.inc(1);
+            }
+        }
+    };
+
+    let file = BufWriter::new(File::create(path).unwrap());
+    match format {
+        PosterFormat::Png => {
+            let mut encoder = png::Encoder::new(file, area.width(), area.height());
+            encoder.set_color(if config.transparent {
+                png::ColorType::Rgba
+            } else {
+                png::ColorType::Rgb
+            });
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder
+                .write_header()
+                .unwrap()
+                .into_stream_writer()
+                .unwrap();
+            read_rows(&mut |row| writer.write_all(row).unwrap());
+            writer.finish().unwrap();
+        }
+        PosterFormat::Tiff => {
+            let bytes = area.width() as u64 * area.height() as u64 * channels as u64;
+            // classic TIFF offsets are 32 bit
+            if bytes < u32::MAX as u64 / 2 {
+                let encoder = tiff::encoder::TiffEncoder::new(file).unwrap();
+                write_tiff(encoder, &area, config.transparent, &mut read_rows);
+            } else {
+                let encoder = tiff::encoder::TiffEncoder::new_big(file).unwrap();
+                write_tiff(encoder, &area, config.transparent, &mut read_rows);
+            }
+        }
+    }
+    progress.finish();
+}
+
+/// Rows of pixels per TIFF strip
+const TIFF_STRIP_ROWS: usize = 64;
+
+fn write_tiff<W: Write + Seek, K: tiff::encoder::TiffKind>(
+    mut encoder: tiff::encoder::TiffEncoder<W, K>,
+    area: &PosterArea,
+    transparent: bool,
+    read_rows: &mut RowReader<'_>,
+) {
+    use tiff::encoder::colortype::{RGB8, RGBA8};
+
+    fn write<W, K, C>(
+        mut image: tiff::encoder::ImageEncoder<'_, W, C, K>,
+        read_rows: &mut RowReader<'_>,
+    ) where
+        W: Write + Seek,
+        K: tiff::encoder::TiffKind,
+        C: tiff::encoder::colortype::ColorType<Inner = u8>,
+    {
+        image.rows_per_strip(TIFF_STRIP_ROWS as u32).unwrap();
+        let mut strip = vec![];
+        let mut rows = 0;
+        read_rows(&mut |row| {
+            strip.extend_from_slice(row);
+            rows += 1;
+            if rows == TIFF_STRIP_ROWS {
+                image.write_strip(&strip).unwrap();
+                strip.clear();
+                rows = 0;
+            }
+        });
+        if rows > 0 {
+            image.write_strip(&strip).unwrap();
+        }
+        image.finish().unwrap();
+    }
+
+    if transparent {
+        write(
+            encoder
+                .new_image::<RGBA8>(area.width(), area.height())
+                .unwrap(),
+            read_rows,
+        );
+    } else {
+        write(
+            encoder
+                .new_image::<RGB8>(area.width(), area.height())
+                .unwrap(),
+            read_rows,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::render::{TileFormat, ZoomRange};
+
+    /// Replays three single colour chunks with 64px screenshots into a render with zoom levels
+    /// 20 and 19, chunk -1,-1 is green, 0,0 red and 1,0 blue
+    fn render(output: &Path) -> RenderConfig {
+        let input = output.with_extension("input");
+        fs::create_dir_all(&input).unwrap();
+        let info = serde_json::json!([{
+            "name": "nauvis",
+            "tags": {},
+            "chunks": [{"x": 0, "y": 0}, {"x": 1, "y": 0}, {"x": -1, "y": -1}],
+        }]);
+        fs::write(input.join("info.json"), info.to_string()).unwrap();
+        for (x, y, [r, g, b]) in [
+            (0, 0, [255, 0, 0]),
+            (1, 0, [0, 0, 255]),
+            (-1, -1, [0, 255, 0]),
+        ] {
+            image::RgbaImage::from_pixel(64, 64, image::Rgba([r, g, b, 255]))
+                .save(input.join(format!("nauvis,{x},{y}.png")))
+                .unwrap();
+        }
+        let config = RenderConfig {
+            tile_size: 64,
+            zoom_range: ZoomRange::MinZoom(19),
+            format: TileFormat::Png,
+            encode_threads: Some(2),
+            resize_threads: Some(2),
+            ..Default::default()
+        };
+        crate::replay::replay(&input, output, &config);
+        config
+    }
+
+    fn close(a: &image::Rgb<u8>, b: [u8; 3]) -> bool {
+        a.0.iter().zip(b).all(|(&a, b)| a.abs_diff(b) <= 2)
+    }
+
+    #[test]
+    fn stitch_synthetic_render() {
+        let dir = std::env::temp_dir().join(format!("fbrs-poster-{}", std::process::id()));
+        let output = dir.join("output");
+        let config = render(&output);
+        let matte = config.matte.0;
+
+        // 3x2 chunks of 64px, the chunk at 0,-1 was never rendered and is matte
+        let path = dir.join("full.png");
+        poster(&output, "nauvis", None, None, &path);
+        let image = image::open(&path).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), (192, 128));
+        for (x, y, colour) in [
+            (10, 10, [0, 255, 0]),
+            (100, 10, matte),
+            (64, 64, [255, 0, 0]),
+            (127, 127, [255, 0, 0]),
+            (128, 64, [0, 0, 255]),
+            (191, 127, [0, 0, 255]),
+            (10, 100, matte),
+        ] {
+            let p = image.get_pixel(x, y);
+            assert!(close(p, colour), "{x},{y} is {p:?}");
+        }
+
+        // half the pixels at the lower zoom level
+        let path = dir.join("zoom.png");
+        poster(&output, "nauvis", Some(19), None, &path);
+        let image = image::open(&path).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), (96, 64));
+        assert!(close(image.get_pixel(16, 16), [0, 255, 0]));
+        assert!(close(image.get_pixel(48, 48), [255, 0, 0]));
+        assert!(close(image.get_pixel(80, 48), [0, 0, 255]));
+
+        // a bbox across chunks 0,0 and 1,0 in game tiles, two pixels per tile at max zoom
+        let path = dir.join("bbox.tiff");
+        let bbox = BoundingBox {
+            left: 24.0,
+            top: 4.0,
+            right: 40.0,
+            bottom: 8.0,
+        };
+        poster(&output, "nauvis", None, Some(bbox), &path);
+        let mut decoder = tiff::decoder::Decoder::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (32, 8));
+        let tiff::decoder::DecodingResult::U8(data) = decoder.read_image().unwrap() else {
+            panic!("poster isn't 8 bit");
+        };
+        let image = image::RgbImage::from_raw(32, 8, data).unwrap();
+        assert!(close(image.get_pixel(0, 0), [255, 0, 0]));
+        assert!(close(image.get_pixel(15, 7), [255, 0, 0]));
+        assert!(close(image.get_pixel(16, 0), [0, 0, 255]));
+        assert!(close(image.get_pixel(31, 7), [0, 0, 255]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
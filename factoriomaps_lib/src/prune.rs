@@ -0,0 +1,446 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crop::CropConfig;
+
+/// Which generated chunks are worth rendering
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PruneConfig {
+    /// Chunks rendered around every chunk with entities of `forces` or tags
+    pub margin: u32,
+    /// Also render chunks outside the margin that are enclosed by it and don't reach the edge
+    /// of the generated map
+    pub fill_islands: bool,
+    /// Forces whose entities make a chunk worth rendering, `*` for every force other than
+    /// `enemy` and `neutral`
+    pub forces: Vec<String>,
+    /// Treat chunks with resources like chunks with entities
+    pub resources: bool,
+}
+impl Default for PruneConfig {
+    fn default() -> Self {
+        Self {
+            margin: 4,
+            fill_islands: true,
+            forces: vec!["player".to_owned()],
+            resources: false,
+        }
+    }
+}
+impl PruneConfig {
+    fn selects_force(&self, force: &str) -> bool {
+        self.forces
+            .iter()
+            .any(|f| f == force || (f == "*" && force != "enemy" && force != "neutral"))
+    }
+}
+
+/// File in the output the survey run reports every generated chunk in
+pub const FACTS_FILE: &str = "chunks.json";
+
+/// Facts about every generated chunk of a surface reported by the mod in [`FACTS_FILE`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SurfaceFacts {
+    pub name: String,
+    pub chunks: Vec<ChunkFacts>,
+    /// Chart tags of every force, used to crop to the area around them
+    #[serde(default)]
+    pub tags: Vec<TagFacts>,
+}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChunkFacts {
+    pub x: i32,
+    pub y: i32,
+    /// Forces without entities are left out
+    #[serde(default)]
+    pub entities: Vec<ForceEntities>,
+    /// Number of chart tags of any force
+    #[serde(default)]
+    pub tags: u32,
+    /// Number of resource entities
+    #[serde(default)]
+    pub resources: u32,
+    /// Only reported for incremental renders
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForceEntities {
+    pub force: String,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagFacts {
+    pub x: f64,
+    pub y: f64,
+    pub text: String,
+}
+
+/// Chunk in the shape the mod reads from the selected chunks in its settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelectedChunk {
+    pub x: i32,
+    pub y: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Why a chunk is or isn't rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChunkClass {
+    /// Has entities of a selected force, tags or resources if enabled
+    Seed,
+    /// Within the margin of a seed
+    Margin,
+    /// Enclosed by seeds and margin chunks, `area` numbers the connected areas outside the
+    /// margin
+    Island { area: u32 },
+    /// Connected to the edge of the generated map without passing the margin
+    Pruned { area: u32 },
+}
+impl ChunkClass {
+    pub fn rendered(&self) -> bool {
+        !matches!(self, ChunkClass::Pruned { .. })
+    }
+}
+
+const NEIGHBORS: [(i32, i32); 4] = [(0, -1), (0, 1), (-1, 0), (1, 0)];
+
+/// Classifies every chunk in `chunks`, chunks are only connected to generated neighbors
+pub fn classify(config: &PruneConfig, chunks: &[ChunkFacts]) -> HashMap<(i32, i32), ChunkClass> {
+    let generated: HashSet<(i32, i32)> = chunks.iter().map(|c| (c.x, c.y)).collect();
+    let neighbors = |(x, y): (i32, i32)| {
+        NEIGHBORS
+            .iter()
+            .map(move |(dx, dy)| (x + dx, y + dy))
+            .filter(|n| generated.contains(n))
+    };
+
+    let mut classes = HashMap::new();
+    let mut queue = VecDeque::new();
+    for chunk in chunks {
+        let seed = chunk.tags > 0
+            || (config.resources && chunk.resources > 0)
+            || chunk
+                .entities
+                .iter()
+                .any(|e| e.count > 0 && config.selects_force(&e.force));
+        if seed {
+            classes.insert((chunk.x, chunk.y), (ChunkClass::Seed, 0));
+            queue.push_back((chunk.x, chunk.y));
+        }
+    }
+
+    // breadth first so every chunk gets its shortest distance to a seed
+    while let Some(chunk) = queue.pop_front() {
+        let distance = classes[&chunk].1;
+        if distance == config.margin {
+            continue;
+        }
+        for neighbor in neighbors(chunk) {
+            if let Entry::Vacant(entry) = classes.entry(neighbor) {
+                entry.insert((ChunkClass::Margin, distance + 1));
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    let mut classes: HashMap<(i32, i32), ChunkClass> = classes
+        .into_iter()
+        .map(|(k, (class, _))| (k, class))
+        .collect();
+
+    // flood fill the remaining chunks, areas touching the edge of the generated map are pruned
+    let mut area_id = 0;
+    for start in &generated {
+        if classes.contains_key(start) {
+            continue;
+        }
+        area_id += 1;
+        let pruned = ChunkClass::Pruned { area: area_id };
+        let mut area = vec![*start];
+        let mut to_visit = vec![*start];
+        let mut edge = false;
+        classes.insert(*start, pruned);
+        while let Some(chunk) = to_visit.pop() {
+            if neighbors(chunk).count() < NEIGHBORS.len() {
+                edge = true;
+            }
+            for neighbor in neighbors(chunk) {
+                if let Entry::Vacant(entry) = classes.entry(neighbor) {
+                    entry.insert(pruned);
+                    area.push(neighbor);
+                    to_visit.push(neighbor);
+                }
+            }
+        }
+        if config.fill_islands && !edge {
+            for chunk in area {
+                classes.insert(chunk, ChunkClass::Island { area: area_id });
+            }
+        }
+    }
+    classes
+}
+
+/// Returns the chunks to render by surface after cropping, surfaces without any are left out
+pub fn select(
+    config: &PruneConfig,
+    crop: &CropConfig,
+    surfaces: &[SurfaceFacts],
+) -> HashMap<String, Vec<SelectedChunk>> {
+    surfaces
+        .iter()
+        .filter(|surface| crop.includes_surface(&surface.name))
+        .filter_map(|surface| {
+            let classes = classify(config, &surface.chunks);
+            let tags: Vec<([f64; 2], &str)> = surface
+                .tags
+                .iter()
+                .map(|t| ([t.x, t.y], t.text.as_str()))
+                .collect();
+            let chunks: Vec<SelectedChunk> = surface
+                .chunks
+                .iter()
+                .filter(|c| classes[&(c.x, c.y)].rendered())
+                .filter(|c| crop.includes_chunk(c.x, c.y, &tags))
+                .map(|c| SelectedChunk {
+                    x: c.x,
+                    y: c.y,
+                    hash: c.hash.clone(),
+                })
+                .collect();
+            (!chunks.is_empty()).then(|| (surface.name.clone(), chunks))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a grid where `#` is a seed, `.` an empty generated chunk and ` ` not generated
+    fn grid(rows: &[&str]) -> Vec<ChunkFacts> {
+        let mut chunks = vec![];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                let mut chunk = ChunkFacts {
+                    x: x as i32,
+                    y: y as i32,
+                    ..Default::default()
+                };
+                match c {
+                    '#' => chunk.entities.push(ForceEntities {
+                        force: "player".to_owned(),
+                        count: 1,
+                    }),
+                    '.' => {}
+                    _ => continue,
+                }
+                chunks.push(chunk);
+            }
+        }
+        chunks
+    }
+
+    fn rendered(config: &PruneConfig, chunks: &[ChunkFacts]) -> Vec<String> {
+        let classes = classify(config, chunks);
+        let width = chunks.iter().map(|c| c.x).max().unwrap() + 1;
+        let height = chunks.iter().map(|c| c.y).max().unwrap() + 1;
+        (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| match classes.get(&(x, y)) {
+                        Some(ChunkClass::Seed) => '#',
+                        Some(ChunkClass::Margin) => 'm',
+                        Some(ChunkClass::Island { .. }) => 'i',
+                        Some(ChunkClass::Pruned { .. }) => '.',
+                        None => ' ',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn margin_around_seeds() {
+        let config = PruneConfig {
+            margin: 1,
+            ..Default::default()
+        };
+        let chunks = grid(&["......", "......", "..#...", "......", "......"]);
+        assert_eq!(
+            rendered(&config, &chunks),
+            ["......", "..m...", ".m#m..", "..m...", "......"]
+        );
+    }
+
+    #[test]
+    fn margin_does_not_cross_ungenerated_chunks() {
+        let config = PruneConfig {
+            margin: 2,
+            ..Default::default()
+        };
+        let chunks = grid(&["#. ..."]);
+        assert_eq!(rendered(&config, &chunks), ["#m ..."]);
+    }
+
+    #[test]
+    fn enclosed_islands_are_filled() {
+        let config = PruneConfig {
+            margin: 0,
+            ..Default::default()
+        };
+        let chunks = grid(&[".......", ".#####.", ".#..##.", ".#####.", "......."]);
+        assert_eq!(
+            rendered(&config, &chunks),
+            [".......", ".#####.", ".#ii##.", ".#####.", "......."]
+        );
+
+        let config = PruneConfig {
+            fill_islands: false,
+            ..config
+        };
+        assert_eq!(
+            rendered(&config, &chunks),
+            [".......", ".#####.", ".#..##.", ".#####.", "......."]
+        );
+    }
+
+    #[test]
+    fn areas_are_numbered_separately() {
+        let config = PruneConfig {
+            margin: 0,
+            ..Default::default()
+        };
+        let chunks = grid(&["#####", "#.#.#", "#####"]);
+        let classes = classify(&config, &chunks);
+        let (ChunkClass::Island { area: a }, ChunkClass::Island { area: b }) =
+            (classes[&(1, 1)], classes[&(3, 1)])
+        else {
+            panic!("both holes should be islands");
+        };
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn islands_reaching_the_edge_are_pruned() {
+        let config = PruneConfig {
+            margin: 0,
+            ..Default::default()
+        };
+        let chunks = grid(&[".....", ".###.", ".#.#.", ".#.#.", "....."]);
+        assert_eq!(
+            rendered(&config, &chunks),
+            [".....", ".###.", ".#.#.", ".#.#.", "....."]
+        );
+    }
+
+    #[test]
+    fn force_selection() {
+        let mut chunks = grid(&["...", "...", "..."]);
+        chunks[4].entities.push(ForceEntities {
+            force: "enemy".to_owned(),
+            count: 3,
+        });
+        chunks[0].entities.push(ForceEntities {
+            force: "allies".to_owned(),
+            count: 1,
+        });
+        chunks[8].resources = 10;
+
+        let config = PruneConfig {
+            margin: 0,
+            fill_islands: false,
+            ..Default::default()
+        };
+        assert_eq!(rendered(&config, &chunks), ["...", "...", "..."]);
+
+        let config = PruneConfig {
+            forces: vec!["*".to_owned()],
+            ..config
+        };
+        assert_eq!(rendered(&config, &chunks), ["#..", "...", "..."]);
+
+        let config = PruneConfig {
+            forces: vec!["enemy".to_owned()],
+            resources: true,
+            ..config
+        };
+        assert_eq!(rendered(&config, &chunks), ["...", ".#.", "..#"]);
+    }
+
+    #[test]
+    fn select_leaves_out_empty_surfaces() {
+        let config = PruneConfig {
+            margin: 0,
+            ..Default::default()
+        };
+        let surfaces = [
+            SurfaceFacts {
+                name: "nauvis".to_owned(),
+                chunks: grid(&["...", ".#.", "..."]),
+                tags: vec![],
+            },
+            SurfaceFacts {
+                name: "empty".to_owned(),
+                chunks: grid(&["...", "...", "..."]),
+                tags: vec![],
+            },
+        ];
+        let selected = select(&config, &CropConfig::default(), &surfaces);
+        assert_eq!(selected.len(), 1);
+        let chunks = &selected["nauvis"];
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].x, chunks[0].y), (1, 1));
+    }
+
+    #[test]
+    fn select_applies_crop() {
+        let config = PruneConfig {
+            margin: 1,
+            ..Default::default()
+        };
+        let surfaces = [
+            SurfaceFacts {
+                name: "nauvis".to_owned(),
+                chunks: grid(&["...", ".#.", "..."]),
+                tags: vec![],
+            },
+            SurfaceFacts {
+                name: "platform-1".to_owned(),
+                chunks: grid(&["#"]),
+                tags: vec![],
+            },
+        ];
+        let crop = CropConfig {
+            exclude: vec!["platform-*".to_owned()],
+            area: Some(crate::crop::CropArea::BoundingBox(
+                "0,0,64,40".parse().unwrap(),
+            )),
+            ..Default::default()
+        };
+        let selected = select(&config, &crop, &surfaces);
+        assert_eq!(selected.len(), 1);
+        let mut chunks: Vec<_> = selected["nauvis"].iter().map(|c| (c.x, c.y)).collect();
+        chunks.sort();
+        assert_eq!(chunks, [(0, 1), (1, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn tags_are_seeds() {
+        let mut chunks = grid(&["...", "...", "..."]);
+        chunks[4].tags = 1;
+        let config = PruneConfig {
+            margin: 0,
+            forces: vec![],
+            ..Default::default()
+        };
+        assert_eq!(rendered(&config, &chunks), ["...", ".#.", "..."]);
+    }
+}
@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
+use std::io::Write;
 use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crossbeam::channel::{Receiver, Sender};
 use crossbeam::thread::Scope;
@@ -15,16 +17,429 @@ use include_dir::{include_dir, Dir};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
-//const TILE_SIZE: u32 = 2048;
-const TILE_SIZE: u32 = 1024;
-const MAX_ZOOM: i32 = 20;
-const NUM_PARTS: u32 = 2;
-const PART_SIZE: u32 = TILE_SIZE / NUM_PARTS;
-
-const TILE_EXTENSION: &str = "jpg";
+use crate::crop::CropConfig;
+use crate::encoder::{QoiEncoder, TileEncoder};
+use crate::export::ExportKind;
+use crate::overlay::PruningOverlay;
+use crate::prune::{PruneConfig, SurfaceFacts};
+use crate::sink::{OutputSink, PathScheme, SinkKind, SurfaceGrid, TileLayout};
 
 static WEB: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/web");
 
+/// Tile geometry shared by the CLI, the injected lib and the mod
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderConfig {
+    /// Size in pixels of the screenshot taken for each chunk
+    pub tile_size: u32,
+    /// Number of parts each tile is split into along each axis when written
+    pub num_parts: u32,
+    /// Zoom level of the chunk screenshots
+    pub max_zoom: i32,
+    /// How far each surface is zoomed out from `max_zoom`
+    pub zoom_range: ZoomRange,
+    /// Encoding of the written tile parts
+    pub format: TileFormat,
+    /// Quality of lossy formats, 0-100
+    pub quality: u8,
+    /// Which chunks of each surface are rendered
+    pub prune: PruneConfig,
+    /// Add a layer showing how each chunk was classified by `prune`
+    pub debug_pruning: bool,
+    /// Which surfaces and which part of them are rendered, applied after `prune`
+    pub crop: CropConfig,
+    /// Looks each surface is rendered with, each becomes a layer named `<surface>/<variant>`.
+    /// Surfaces are rendered once with the default look if empty
+    pub variants: Vec<Variant>,
+    /// Screenshot parameters passed to the mod
+    pub screenshot: ScreenshotConfig,
+    /// Keep the alpha channel instead of flattening onto `matte`
+    pub transparent: bool,
+    /// Colour transparent pixels are flattened onto, also used as the viewer background
+    pub matte: Rgb,
+    /// Filter used to downscale parent tiles
+    pub filter: ResizeFilter,
+    /// Downscale parent tiles in linear light instead of on sRGB values
+    pub linear: bool,
+    /// Bytes of finished tiles kept in memory while waiting for their siblings before
+    /// spilling the oldest to disk
+    pub memory_budget: Option<usize>,
+    /// Number of jobs each work queue holds before the sender blocks
+    pub queue_depth: usize,
+    /// Threads writing tile parts, defaults to the available parallelism
+    pub encode_threads: Option<usize>,
+    /// Threads building parent tiles, defaults to the available parallelism
+    pub resize_threads: Option<usize>,
+    /// Only render chunks that changed since the manifest of the previous render
+    pub incremental: bool,
+    /// Continue an interrupted render from its journal
+    pub resume: bool,
+    /// Where tile parts are written
+    pub sink: SinkKind,
+    /// Paths of tile parts written by the directory sink
+    pub path_scheme: PathScheme,
+    /// Extra layouts written from the finished tiles
+    pub exports: Vec<ExportKind>,
+    /// Absolute URL the `iiif` export directory is served from, used as the base of IIIF image
+    /// ids. Required by the `iiif` export
+    pub iiif_base_url: Option<String>,
+}
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            tile_size: 1024,
+            num_parts: 2,
+            max_zoom: 20,
+            zoom_range: ZoomRange::Auto,
+            prune: PruneConfig::default(),
+            debug_pruning: false,
+            crop: CropConfig::default(),
+            variants: vec![],
+            screenshot: ScreenshotConfig::default(),
+            format: TileFormat::Jpeg,
+            quality: 80,
+            transparent: false,
+            matte: Rgb([27, 45, 51]),
+            filter: ResizeFilter::Lanczos3,
+            linear: false,
+            memory_budget: None,
+            queue_depth: 16,
+            encode_threads: None,
+            resize_threads: None,
+            incremental: false,
+            resume: false,
+            sink: SinkKind::Directory,
+            path_scheme: PathScheme::default(),
+            exports: vec![],
+            iiif_base_url: None,
+        }
+    }
+}
+impl RenderConfig {
+    pub fn part_size(&self) -> u32 {
+        self.tile_size / self.num_parts
+    }
+    /// Returns the built-in encoder selected by `format` and `quality`
+    pub fn encoder(&self) -> Arc<dyn TileEncoder> {
+        self.format.encoder(self.quality)
+    }
+    /// Whether tiles written with `other` look the same as tiles written with this config
+    pub fn output_compatible(&self, other: &RenderConfig) -> bool {
+        self.tile_size == other.tile_size
+            && self.num_parts == other.num_parts
+            && self.max_zoom == other.max_zoom
+            && self.format == other.format
+            && self.quality == other.quality
+            && self.transparent == other.transparent
+            && self.matte == other.matte
+            && self.filter == other.filter
+            && self.linear == other.linear
+            && self.path_scheme == other.path_scheme
+            && self.variants == other.variants
+            && self.screenshot == other.screenshot
+    }
+}
+
+/// Record of a finished render used to skip unchanged chunks on the next incremental render
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub config: RenderConfig,
+    pub surfaces: HashMap<String, SurfaceManifest>,
+}
+/// Append-only record of tiles whose parts are on disk, used to resume an interrupted render
+///
+/// The first line is the config of the render, every following line a finished tile.
+pub struct Journal {
+    file: fs::File,
+}
+impl Journal {
+    pub fn path<P: AsRef<Path>>(output: P) -> PathBuf {
+        output.as_ref().join("journal.jsonl")
+    }
+    /// Returns the tiles finished by an interrupted render into `output` with a compatible config
+    pub fn read<P: AsRef<Path>>(output: P, config: &RenderConfig) -> Option<HashSet<Tile>> {
+        let data = fs::read_to_string(Self::path(output)).ok()?;
+        let mut lines = data.lines();
+        let previous: RenderConfig = serde_json::from_str(lines.next()?).ok()?;
+        if !previous.output_compatible(config) {
+            return None;
+        }
+        // the last line may have been cut off by the crash
+        Some(lines.filter_map(|l| serde_json::from_str(l).ok()).collect())
+    }
+    /// Max zoom chunks finished by an interrupted render, keyed by surface and then "x,y"
+    pub fn read_chunks<P: AsRef<Path>>(
+        output: P,
+        config: &RenderConfig,
+    ) -> HashMap<String, Vec<String>> {
+        let mut chunks: HashMap<String, Vec<String>> = HashMap::new();
+        for tile in Self::read(output, config).unwrap_or_default() {
+            if tile.zoom == config.max_zoom {
+                chunks
+                    .entry(tile.surface)
+                    .or_default()
+                    .push(format!("{},{}", tile.x, tile.y));
+            }
+        }
+        chunks
+    }
+    /// Starts a new journal or, if `append`, continues the existing one
+    fn open(output: &Path, config: &RenderConfig, append: bool) -> Journal {
+        let path = Self::path(output);
+        fs::create_dir_all(output).unwrap();
+        let file = if append {
+            fs::OpenOptions::new().append(true).open(path).unwrap()
+        } else {
+            let mut file = fs::File::create(path).unwrap();
+            writeln!(file, "{}", serde_json::to_string(config).unwrap()).unwrap();
+            file
+        };
+        Journal { file }
+    }
+    fn record(&mut self, tile: &Tile) {
+        writeln!(self.file, "{}", serde_json::to_string(tile).unwrap()).unwrap();
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SurfaceManifest {
+    pub min_zoom: i32,
+    /// Content hash reported by the mod keyed by "x,y"
+    pub chunks: HashMap<String, Option<String>>,
+}
+impl Manifest {
+    const FILE: &'static str = "manifest.json";
+    pub fn path<P: AsRef<Path>>(output: P) -> PathBuf {
+        output.as_ref().join(Self::FILE)
+    }
+    /// Returns the manifest of the last finished render into `output`
+    pub fn read<P: AsRef<Path>>(output: P) -> Option<Manifest> {
+        let data = fs::read(Self::path(output)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+    /// Returns the manifest of a previous render into `output` if it can be reused with `config`
+    pub fn read_compatible<P: AsRef<Path>>(output: P, config: &RenderConfig) -> Option<Manifest> {
+        let manifest = Self::read(output)?;
+        manifest
+            .config
+            .output_compatible(config)
+            .then_some(manifest)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TileFormat {
+    Jpeg,
+    Webp,
+    WebpLossless,
+    Png,
+    Qoi,
+}
+impl std::str::FromStr for TileFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" | "jpg" => Ok(TileFormat::Jpeg),
+            "webp" => Ok(TileFormat::Webp),
+            "webp-lossless" => Ok(TileFormat::WebpLossless),
+            "png" => Ok(TileFormat::Png),
+            "qoi" => Ok(TileFormat::Qoi),
+            _ => Err(format!(
+                "unknown tile format \"{s}\", expected one of: jpeg, webp, webp-lossless, png, qoi"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeFilter {
+    Box,
+    Bilinear,
+    CatmullRom,
+    Lanczos3,
+}
+impl ResizeFilter {
+    fn filter_type(&self) -> fr::FilterType {
+        match self {
+            ResizeFilter::Box => fr::FilterType::Box,
+            ResizeFilter::Bilinear => fr::FilterType::Bilinear,
+            ResizeFilter::CatmullRom => fr::FilterType::CatmullRom,
+            ResizeFilter::Lanczos3 => fr::FilterType::Lanczos3,
+        }
+    }
+}
+impl std::str::FromStr for ResizeFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(ResizeFilter::Box),
+            "bilinear" => Ok(ResizeFilter::Bilinear),
+            "catmull-rom" => Ok(ResizeFilter::CatmullRom),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            _ => Err(format!(
+                "unknown resize filter \"{s}\", expected one of: box, bilinear, catmull-rom, lanczos3"
+            )),
+        }
+    }
+}
+
+/// One look of a surface, parsed from `name[:option,...]` with the options `daytime=<0-1>` and
+/// `alt-mode=on|off`
+///
+/// `day`, `night` and `no-alt-mode` work without options.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Variant {
+    pub name: String,
+    /// Time of day of the screenshots, 0 is noon and 0.5 midnight with lights on
+    pub daytime: f64,
+    /// Alt-mode icons and overlays
+    pub show_entity_info: bool,
+}
+impl std::str::FromStr for Variant {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, options) = s.split_once(':').unwrap_or((s, ""));
+        if name.is_empty() || name.contains(['/', ',', '\\']) {
+            return Err(format!("invalid variant name \"{name}\""));
+        }
+        let mut variant = Variant {
+            name: name.to_owned(),
+            daytime: if name == "night" { 0.5 } else { 0.0 },
+            show_entity_info: name != "no-alt-mode",
+        };
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("daytime", value)) => {
+                    variant.daytime = value
+                        .parse()
+                        .ok()
+                        .filter(|d| (0.0..=1.0).contains(d))
+                        .ok_or_else(|| format!("invalid daytime \"{value}\", expected 0-1"))?
+                }
+                Some(("alt-mode", "on")) => variant.show_entity_info = true,
+                Some(("alt-mode", "off")) => variant.show_entity_info = false,
+                _ => {
+                    return Err(format!(
+                        "unknown variant option \"{option}\", expected one of: daytime=<0-1>, alt-mode=on|off"
+                    ))
+                }
+            }
+        }
+        Ok(variant)
+    }
+}
+
+/// How the mod takes screenshots, [`Variant`]s override `daytime` and `show_entity_info`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScreenshotConfig {
+    /// Smooth edges at the cost of slower screenshots
+    pub anti_alias: bool,
+    /// Time of day, 0 is noon and 0.5 midnight. Surfaces are lit as always day if `None`
+    pub daytime: Option<f64>,
+    /// Alt-mode icons and overlays
+    pub show_entity_info: bool,
+    /// Remove decoratives such as grass and flowers before taking screenshots
+    pub hide_decoratives: bool,
+}
+impl Default for ScreenshotConfig {
+    fn default() -> Self {
+        Self {
+            anti_alias: false,
+            daytime: None,
+            show_entity_info: true,
+            hide_decoratives: false,
+        }
+    }
+}
+
+/// Lowest zoom level built for a surface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZoomRange {
+    /// Zoom out until the surface fits in about 32 parts
+    Auto,
+    /// Zoom out to this zoom level
+    MinZoom(i32),
+    /// Build this many zoom levels including `max_zoom`
+    Levels(u32),
+    /// Zoom out until the whole surface fits in this many pixels along its longest axis
+    Overview(u32),
+}
+impl ZoomRange {
+    /// Most zoom levels a surface can have so that `1 << (max_zoom - zoom)` fits in an `i32`
+    pub const MAX_LEVELS: u32 = 31;
+
+    /// Returns the lowest zoom level for a surface spanning the chunks `min` to `max` inclusive
+    pub fn min_zoom(&self, config: &RenderConfig, min: (i32, i32), max: (i32, i32)) -> i32 {
+        let zoom = match *self {
+            ZoomRange::Auto => {
+                let extent = (1 - min.0).max(1 - min.1).max(max.0).max(max.1);
+                config.max_zoom - extent.ilog2() as i32 - 5
+            }
+            ZoomRange::MinZoom(zoom) => zoom.min(config.max_zoom),
+            ZoomRange::Levels(levels) => config.max_zoom + 1 - levels.max(1) as i32,
+            ZoomRange::Overview(px) => {
+                let chunks = (max.0 - min.0).max(max.1 - min.1) as u64 + 1;
+                let size = chunks * config.tile_size as u64;
+                // every zoom level below max zoom halves the size
+                let halvings = size.div_ceil(px.max(1) as u64).next_power_of_two().ilog2();
+                config.max_zoom - halvings as i32
+            }
+        };
+        zoom.max(config.max_zoom + 1 - Self::MAX_LEVELS as i32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rgb(pub [u8; 3]);
+impl std::str::FromStr for Rgb {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let err = || format!("invalid colour \"{s}\", expected hex such as #1b2d33");
+        if hex.len() != 6 {
+            return Err(err());
+        }
+        let mut rgb = [0; 3];
+        for (i, c) in rgb.iter_mut().enumerate() {
+            *c = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or_else(err)?, 16)
+                .map_err(|_| err())?;
+        }
+        Ok(Rgb(rgb))
+    }
+}
+
+/// Area in game coordinates, parsed from `left,top,right,bottom`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub left: f64,
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+}
+impl std::str::FromStr for BoundingBox {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid bounding box \"{s}\", expected left,top,right,bottom");
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|_| err()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [x1, y1, x2, y2] = values[..] else {
+            return Err(err());
+        };
+        Ok(BoundingBox {
+            left: x1.min(x2),
+            top: y1.min(y2),
+            right: x1.max(x2),
+            bottom: y1.max(y2),
+        })
+    }
+}
+
 pub struct VirtualFile {
     pub path: PathBuf,
     pub data: Vec<u8>,
@@ -40,6 +455,8 @@ impl VirtualFile {
 pub enum MessageToMain {
     Finished,
     Killed,
+    /// A screenshot couldn't be processed, stops the render
+    Failed(String),
     File(VirtualFile),
     FinishWriteParts { tile: Tile, image: DynamicImage },
     FinishBuildParent { parent: Tile, image: DynamicImage },
@@ -52,11 +469,33 @@ pub enum MessageToWorker {
     },
     TileBuildParent {
         parent: Tile,
-        children: Vec<(Tile, DynamicImage)>,
+        children: Vec<(Tile, ChildImage)>,
     },
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
+/// Finished child tile handed to the worker building its parent
+pub enum ChildImage {
+    Memory(DynamicImage),
+    /// QOI encoded tile in the spill cache
+    Spilled(PathBuf),
+    /// Unchanged tile written by a previous render
+    Existing(Tile),
+}
+impl ChildImage {
+    fn load(self, sink: &dyn OutputSink, config: &RenderConfig) -> DynamicImage {
+        match self {
+            ChildImage::Existing(tile) => tile_read_parts(sink, config, &tile),
+            ChildImage::Memory(image) => image,
+            ChildImage::Spilled(path) => {
+                let data = fs::read(&path).unwrap();
+                fs::remove_file(&path).unwrap();
+                image::load_from_memory_with_format(&data, image::ImageFormat::Qoi).unwrap()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Tile {
     surface: String,
     zoom: i32,
@@ -65,10 +504,10 @@ pub struct Tile {
 }
 
 impl Tile {
-    pub fn new_max_zoom(surface: String, x: i32, y: i32) -> Self {
+    pub fn new_max_zoom(config: &RenderConfig, surface: String, x: i32, y: i32) -> Self {
         Tile {
             surface,
-            zoom: MAX_ZOOM,
+            zoom: config.max_zoom,
             x,
             y
         }
@@ -110,18 +549,107 @@ impl Tile {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct SurfaceInfo {
+    /// Name of the layer, `<surface>/<variant>` once split into variants
     name: String,
+    /// Factorio surface the layer shows, not reported by the mod
+    #[serde(default)]
+    surface: String,
+    #[serde(default)]
+    variant: Option<String>,
+    /// Only reported by Factorio 2.0
+    #[serde(default)]
+    kind: Option<SurfaceKind>,
+    /// Name shown in the viewer, the surface name if not reported
+    #[serde(default)]
+    label: Option<String>,
     tags: HashMap<String, Vec<Tag>>,
-    chunks: Vec<Coordinate<i32>>,
+    chunks: Vec<Chunk>,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SurfaceKind {
+    Planet,
+    /// Space platform, labelled with the name the player gave it
+    Platform,
+}
+/// Splits every surface into one layer per variant, the mod names screenshots to match
+fn split_variants(info: Vec<SurfaceInfo>, config: &RenderConfig) -> Vec<SurfaceInfo> {
+    info.into_iter()
+        .flat_map(|surface| {
+            if config.variants.is_empty() {
+                return vec![SurfaceInfo {
+                    surface: surface.name.clone(),
+                    ..surface
+                }];
+            }
+            config
+                .variants
+                .iter()
+                .map(|variant| SurfaceInfo {
+                    name: format!("{}/{}", surface.name, variant.name),
+                    surface: surface.name.clone(),
+                    variant: Some(variant.name.clone()),
+                    ..surface.clone()
+                })
+                .collect()
+        })
+        .collect()
+}
+/// Applies the same crop the screenshots were selected with, so the tile set and manifest agree
+/// with them
+fn crop(info: &mut Vec<SurfaceInfo>, config: &RenderConfig) {
+    info.retain(|surface| config.crop.includes_surface(&surface.surface));
+    for surface in info {
+        let tags: Vec<([f64; 2], &str)> = surface
+            .tags
+            .values()
+            .flatten()
+            .map(|t| ([t.position.x, t.position.y], t.text.as_str()))
+            .collect();
+        surface
+            .chunks
+            .retain(|c| config.crop.includes_chunk(c.x, c.y, &tags));
+    }
+}
+/// Returns every screenshot the mod takes for `info_json` as the tile it becomes and its file
+/// name without extension, `<surface>,<x>,<y>[,<variant>]`
+pub(crate) fn screenshot_names(info_json: &[u8], config: &RenderConfig) -> Vec<(Tile, String)> {
+    let mut info = split_variants(serde_json::from_slice(info_json).unwrap(), config);
+    crop(&mut info, config);
+    let mut names = vec![];
+    for surface in info {
+        for chunk in &surface.chunks {
+            let mut name = format!("{},{},{}", surface.surface, chunk.x, chunk.y);
+            if let Some(variant) = &surface.variant {
+                name = format!("{name},{variant}");
+            }
+            let tile = Tile::new_max_zoom(config, surface.name.clone(), chunk.x, chunk.y);
+            names.push((tile, name));
+        }
+    }
+    names
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Chunk {
+    x: i32,
+    y: i32,
+    /// Only reported for incremental renders
+    #[serde(default)]
+    hash: Option<String>,
+}
+impl Chunk {
+    fn key(&self) -> String {
+        format!("{},{}", self.x, self.y)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Coordinate<T> {
     x: T,
     y: T,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Tag {
     position: Coordinate<f64>,
     text: String,
@@ -129,6 +657,10 @@ struct Tag {
 
 enum TileState {
     Loaded(DynamicImage),
+    /// Loaded but moved to the spill cache to stay within the memory budget
+    Spilled(PathBuf),
+    /// Unchanged since the previous render and read back from the output
+    Existing,
     Waiting,
     Processed,
 }
@@ -139,6 +671,8 @@ impl std::fmt::Debug for TileState {
             "{}",
             match self {
                 TileState::Loaded(_) => "Loaded",
+                TileState::Spilled(_) => "Spilled",
+                TileState::Existing => "Existing",
                 TileState::Waiting => "Waiting",
                 TileState::Processed => "Processed",
             }
@@ -147,64 +681,124 @@ impl std::fmt::Debug for TileState {
 }
 
 impl TileState {
-    fn take(&mut self) -> DynamicImage {
+    fn take(&mut self, tile: &Tile) -> ChildImage {
         let old = std::mem::replace(self, TileState::Processed);
-        if let TileState::Loaded(img) = old {
-            img
-        } else {
-            panic!("TileState is not Loaded");
+        match old {
+            TileState::Loaded(img) => ChildImage::Memory(img),
+            TileState::Spilled(path) => ChildImage::Spilled(path),
+            TileState::Existing => ChildImage::Existing(tile.clone()),
+            _ => panic!("TileState is not Loaded"),
         }
     }
 }
 
 #[derive(Debug)]
 struct ThreadContext {
+    config: RenderConfig,
     info: Vec<SurfaceInfo>,
     tiles: HashMap<Tile, TileState>,
     min_zoom: HashMap<String, i32>,
+    grids: HashMap<String, SurfaceGrid>,
     progress: ProgressBar,
     loaded_tiles: usize,
     total_tiles: usize,
+    spill_dir: PathBuf,
+    /// Loaded tiles in the order they finished, oldest are spilled first
+    spill_queue: VecDeque<Tile>,
+    loaded_bytes: usize,
+    peak_bytes: usize,
+    spilled_tiles: usize,
 }
 impl ThreadContext {
-    fn new(info: Vec<SurfaceInfo>) -> ThreadContext {
+    fn new(
+        mut info: Vec<SurfaceInfo>,
+        config: &RenderConfig,
+        spill_dir: PathBuf,
+        previous: Option<&Manifest>,
+        finished: &HashSet<Tile>,
+    ) -> ThreadContext {
+        crop(&mut info, config);
+
         let mut tiles = HashMap::new();
         let mut min_zoom = HashMap::new();
+        let mut grids = HashMap::new();
+        // tiles that have to be rendered in this run, everything else is reused
+        let mut dirty = HashSet::new();
 
         for surface in &info {
-            let Some(first) = surface.chunks.first() else {
+            let chunks = surface.chunks.iter().map(|c| (c.x, c.y));
+            let Some(grid) = SurfaceGrid::new(config, chunks) else {
                 continue;
             };
-
-            let mut min_x = first.x;
-            let mut max_x = first.x;
-            let mut min_y = first.y;
-            let mut max_y = first.y;
-
-            for chunk in &surface.chunks {
-                min_x = min_x.min(chunk.x);
-                max_x = max_x.max(chunk.x);
-                min_y = min_y.min(chunk.y);
-                max_y = max_y.max(chunk.y)
-            }
-            let max = (1 - min_x).max(1 - min_y).max(max_x).max(max_y);
-            let mz = MAX_ZOOM - max.ilog2() as i32 - 6;
+            // tiles are built down to and excluding this zoom level
+            let mz = grid.min_zoom - 1;
             min_zoom.insert(surface.name.to_owned(), mz);
+            grids.insert(surface.name.to_owned(), grid);
 
             for chunk in &surface.chunks {
-                let mut tile = Tile::new_max_zoom(surface.name.to_owned(), chunk.x, chunk.y);
+                let mut tile =
+                    Tile::new_max_zoom(config, surface.name.to_owned(), chunk.x, chunk.y);
 
                 loop {
                     if tile.zoom <= mz || tiles.contains_key(&tile) {
                         break;
                     }
-                    tiles.insert(tile.clone(), TileState::Waiting);
+                    tiles.insert(tile.clone(), TileState::Processed);
+                    tile = tile.zoom_out();
+                }
+            }
+
+            let previous = previous.and_then(|p| p.surfaces.get(&surface.name));
+
+            let mut changed = vec![];
+            for chunk in &surface.chunks {
+                let unchanged = previous.is_some_and(|p| {
+                    chunk.hash.is_some() && p.chunks.get(&chunk.key()) == Some(&chunk.hash)
+                });
+                if !unchanged {
+                    changed.push((chunk.x, chunk.y));
+                }
+            }
+            if let Some(previous) = previous {
+                // the previous pyramid stopped above these zoom levels if the surface grew
+                for tile in tiles.keys() {
+                    if tile.surface == surface.name && tile.zoom <= previous.min_zoom {
+                        dirty.insert(tile.clone());
+                    }
+                }
+                let current: HashSet<String> = surface.chunks.iter().map(Chunk::key).collect();
+                for key in previous.chunks.keys() {
+                    if !current.contains(key) {
+                        let (x, y) = key.split_once(',').unwrap();
+                        changed.push((x.parse().unwrap(), y.parse().unwrap()));
+                    }
+                }
+            }
+
+            for (x, y) in changed {
+                let mut tile = Tile::new_max_zoom(config, surface.name.to_owned(), x, y);
+                // removed chunks still invalidate ancestors that remain
+                while tile.zoom > mz {
+                    if tiles.contains_key(&tile) {
+                        dirty.insert(tile.clone());
+                    }
                     tile = tile.zoom_out();
                 }
             }
         }
 
-        let progress = ProgressBar::new(tiles.len() as u64);
+        // tiles written before an interrupted render died are read back like unchanged ones
+        dirty.retain(|tile| !finished.contains(tile));
+
+        for (tile, state) in tiles.iter_mut() {
+            if dirty.contains(tile) {
+                *state = TileState::Waiting;
+            } else if dirty.contains(&tile.zoom_out()) {
+                *state = TileState::Existing;
+            }
+        }
+
+        let progress = ProgressBar::new(dirty.len() as u64);
         progress.set_style(
             ProgressStyle::with_template(
                 "{wide_bar} Elapsed: {elapsed}, ETA: {smoothed_eta}",
@@ -222,12 +816,19 @@ impl ThreadContext {
         );
 
         ThreadContext {
+            config: config.clone(),
             info,
-            total_tiles: tiles.len(),
+            total_tiles: dirty.len(),
             min_zoom,
+            grids,
             tiles,
             progress,
             loaded_tiles: 0,
+            spill_dir,
+            spill_queue: VecDeque::new(),
+            loaded_bytes: 0,
+            peak_bytes: 0,
+            spilled_tiles: 0,
         }
     }
 
@@ -235,7 +836,7 @@ impl ThreadContext {
         tile.children()
             .into_iter()
             .all(|tile| match self.tiles.get(&tile) {
-                Some(TileState::Loaded(_)) => true,
+                Some(TileState::Loaded(_) | TileState::Spilled(_) | TileState::Existing) => true,
                 Some(TileState::Waiting) => false,
                 Some(TileState::Processed) => {
                     panic!("Shouldn't be checking already processed tiles")
@@ -248,94 +849,233 @@ impl ThreadContext {
         self.progress.inc(1);
         self.loaded_tiles += 1;
     }
+
+    fn insert_loaded(&mut self, tile: Tile, image: DynamicImage) {
+        self.loaded_bytes += image.as_bytes().len();
+        self.peak_bytes = self.peak_bytes.max(self.loaded_bytes);
+        if self.config.memory_budget.is_some() {
+            self.spill_queue.push_back(tile.clone());
+        }
+        self.tiles.insert(tile, TileState::Loaded(image));
+    }
+
+    fn take(&mut self, tile: &Tile) -> Option<ChildImage> {
+        let image = self.tiles.get_mut(tile)?.take(tile);
+        if let ChildImage::Memory(image) = &image {
+            self.loaded_bytes -= image.as_bytes().len();
+        }
+        Some(image)
+    }
+
+    /// Moves the oldest loaded tiles to disk until back within the memory budget
+    fn spill(&mut self) {
+        let Some(budget) = self.config.memory_budget else {
+            return;
+        };
+        while self.loaded_bytes > budget {
+            let Some(tile) = self.spill_queue.pop_front() else {
+                break;
+            };
+            let Some(state) = self.tiles.get_mut(&tile) else {
+                continue;
+            };
+            if !matches!(state, TileState::Loaded(_)) {
+                // already taken by its parent
+                continue;
+            }
+            let TileState::Loaded(image) = std::mem::replace(state, TileState::Processed) else {
+                unreachable!();
+            };
+            self.loaded_bytes -= image.as_bytes().len();
+
+            let path = self.spill_dir.join(format!(
+                "{},{},{},{}.qoi",
+                tile.surface, tile.zoom, tile.x, tile.y
+            ));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, QoiEncoder.encode(&image.into_rgba8())).unwrap();
+            *state = TileState::Spilled(path);
+            self.spilled_tiles += 1;
+        }
+    }
 }
 struct TilePart {
     x: u32,
     y: u32,
 }
 impl TilePart {
-    fn get_path_components(&self, tile: &Tile) -> (i32, i32, i32) {
+    fn get_path_components(&self, config: &RenderConfig, tile: &Tile) -> (i32, i32, i32) {
         (
             tile.zoom,
-            self.x as i32 + tile.x * NUM_PARTS as i32,
-            self.y as i32 + tile.y * NUM_PARTS as i32,
-        )
-    }
-    fn get_path(&self, tile: &Tile) -> String {
-        let components = self.get_path_components(tile);
-        format!(
-            "{}/{}/{}/{}.{}",
-            tile.surface, components.0, components.1, components.2, TILE_EXTENSION
+            self.x as i32 + tile.x * config.num_parts as i32,
+            self.y as i32 + tile.y * config.num_parts as i32,
         )
     }
 }
-fn get_tile_parts() -> Vec<TilePart> {
+fn get_tile_parts(config: &RenderConfig) -> Vec<TilePart> {
     let mut parts = vec![];
-    for x in 0..NUM_PARTS {
-        for y in 0..NUM_PARTS {
+    for x in 0..config.num_parts {
+        for y in 0..config.num_parts {
             parts.push(TilePart { x, y });
         }
     }
     parts
 }
-fn tile_write_parts<P: AsRef<Path>>(output: P, tile: &Tile, image: &DynamicImage) {
-    for part in get_tile_parts() {
-        let sub_img = image
-            .view(part.x * PART_SIZE, part.y * PART_SIZE, PART_SIZE, PART_SIZE)
+/// Replaces transparent pixels with the matte colour and makes the rest opaque
+pub(crate) fn flatten_alpha(image: &mut image::RgbaImage, matte: Rgb) {
+    let [r, g, b] = matte.0;
+    for p in image.pixels_mut() {
+        if p[3] <= 0x7f {
+            *p = image::Rgba([r, g, b, 0xff]);
+        } else {
+            p[3] = 0xff;
+        }
+    }
+}
+fn tile_write_parts(
+    sink: &dyn OutputSink,
+    config: &RenderConfig,
+    encoder: &dyn TileEncoder,
+    tile: &Tile,
+    image: &DynamicImage,
+) {
+    let part_size = config.part_size();
+    for part in get_tile_parts(config) {
+        let mut sub_img = image
+            .view(part.x * part_size, part.y * part_size, part_size, part_size)
             .to_image();
-        let path = output.as_ref().join("tiles").join(part.get_path(tile));
-        fs::create_dir_all(path.parent().unwrap()).unwrap();
-
-        let dyn_img = DynamicImage::from(sub_img);
-
-        let mut data = vec![];
-        let cur = std::io::Cursor::new(&mut data);
-        let encoder = jpeg_encoder::Encoder::new(cur, 80);
-        let (width, height) = dyn_img.dimensions();
-        let mut bytes = dyn_img.into_bytes();
-        for p in bytes.chunks_mut(4) {
-            if p[3] <= 0x7f {
-                p[0] = 27;
-                p[1] = 45;
-                p[2] = 51;
-                p[3] = 0xff;
-            }
+        if !config.transparent {
+            flatten_alpha(&mut sub_img, config.matte);
+        }
+        let data = encoder.encode(&sub_img);
+
+        let (zoom, x, y) = part.get_path_components(config, tile);
+        sink.write_part(&tile.surface, zoom, x, y, data);
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SRGB_TO_LINEAR: Vec<u16> = (0..=255u8)
+        .map(|c| {
+            let c = c as f32 / 255.0;
+            let l = if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            };
+            (l * 65535.0).round() as u16
+        })
+        .collect();
+    static ref LINEAR_TO_SRGB: Vec<u8> = (0..=65535u16)
+        .map(|l| {
+            let l = l as f32 / 65535.0;
+            let c = if l <= 0.0031308 {
+                l * 12.92
+            } else {
+                1.055 * l.powf(1.0 / 2.4) - 0.055
+            };
+            (c * 255.0).round() as u8
+        })
+        .collect();
+}
+
+/// Converts sRGB RGBA8 to premultiplied linear RGBA16 in native endian bytes
+fn srgb_to_linear_premultiplied(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(src.len() * 2);
+    for p in src.chunks_exact(4) {
+        let a = p[3] as u32;
+        for &c in &p[..3] {
+            let l = SRGB_TO_LINEAR[c as usize] as u32 * a / 255;
+            dst.extend_from_slice(&(l as u16).to_ne_bytes());
+        }
+        dst.extend_from_slice(&(a as u16 * 257).to_ne_bytes());
+    }
+    dst
+}
+
+/// Inverse of [`srgb_to_linear_premultiplied`]
+fn linear_premultiplied_to_srgb(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(src.len() / 2);
+    for p in src.chunks_exact(8) {
+        let channel = |i: usize| u16::from_ne_bytes([p[i * 2], p[i * 2 + 1]]) as u32;
+        let a = channel(3);
+        if a == 0 {
+            dst.extend_from_slice(&[0, 0, 0, 0]);
+            continue;
+        }
+        for i in 0..3 {
+            let l = (channel(i) * 65535 / a).min(65535);
+            dst.push(LINEAR_TO_SRGB[l as usize]);
         }
-        encoder.encode(&bytes, width as u16, height as u16, jpeg_encoder::ColorType::Rgba).unwrap();
+        dst.push(((a + 128) / 257) as u8);
+    }
+    dst
+}
 
-        std::fs::write(path, &*data).unwrap();
+/// Reassembles a tile from the parts written by a previous render
+fn tile_read_parts(sink: &dyn OutputSink, config: &RenderConfig, tile: &Tile) -> DynamicImage {
+    let part_size = config.part_size();
+    let mut image = DynamicImage::new_rgba8(config.tile_size, config.tile_size);
+    for part in get_tile_parts(config) {
+        let (zoom, x, y) = part.get_path_components(config, tile);
+        // left transparent if the previous render never wrote it
+        let data = sink.read_part(&tile.surface, zoom, x, y);
+        if let Some(part_img) = data.and_then(|d| image::load_from_memory(&d).ok()) {
+            image
+                .copy_from(&part_img, part.x * part_size, part.y * part_size)
+                .unwrap();
+        }
     }
+    image
 }
 
-fn image_resize(src: DynamicImage) -> DynamicImage {
+/// Scales `src` to a `size` square with the filter and colour space of `config`
+pub(crate) fn image_resize(src: DynamicImage, size: u32, config: &RenderConfig) -> DynamicImage {
     let width = NonZeroU32::new(src.width()).unwrap();
     let height = NonZeroU32::new(src.height()).unwrap();
-    let src_image = fr::Image::from_vec_u8(
-        width,
-        height,
-        src.into_rgba8().into_raw(),
-        fr::PixelType::U8x4,
-    )
-    .unwrap();
-
-    // Create container for data of destination image
-    let dst_width = NonZeroU32::new(TILE_SIZE).unwrap();
-    let dst_height = NonZeroU32::new(TILE_SIZE).unwrap();
-    let mut dst_image = fr::Image::new(dst_width, dst_height, src_image.pixel_type());
-
-    // Get mutable view of destination image data
-    let mut dst_view = dst_image.view_mut();
-
-    // Create Resizer instance and resize source image
-    // into buffer of destination image
-    let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3));
-    resizer.resize(&src_image.view(), &mut dst_view).unwrap();
-
-    // Divide RGB channels of destination image by alpha
-    //alpha_mul_div.divide_alpha_inplace(&mut dst_view).unwrap();
-    DynamicImage::ImageRgba8(
-        image::RgbaImage::from_raw(TILE_SIZE, TILE_SIZE, dst_image.into_vec()).unwrap(),
-    )
+    let dst_width = NonZeroU32::new(size).unwrap();
+    let dst_height = NonZeroU32::new(size).unwrap();
+
+    let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(config.filter.filter_type()));
+
+    let rgba = if config.linear {
+        // Alpha is premultiplied during the conversion so MulDiv is not needed
+        let src_image = fr::Image::from_vec_u8(
+            width,
+            height,
+            srgb_to_linear_premultiplied(src.into_rgba8().as_raw()),
+            fr::PixelType::U16x4,
+        )
+        .unwrap();
+        let mut dst_image = fr::Image::new(dst_width, dst_height, fr::PixelType::U16x4);
+        resizer
+            .resize(&src_image.view(), &mut dst_image.view_mut())
+            .unwrap();
+        linear_premultiplied_to_srgb(dst_image.buffer())
+    } else {
+        let mut src_image = fr::Image::from_vec_u8(
+            width,
+            height,
+            src.into_rgba8().into_raw(),
+            fr::PixelType::U8x4,
+        )
+        .unwrap();
+
+        // Multiply RGB channels by alpha so transparent pixels don't bleed into their
+        // neighbours, and divide again after resizing
+        let alpha_mul_div = fr::MulDiv::default();
+        alpha_mul_div
+            .multiply_alpha_inplace(&mut src_image.view_mut())
+            .unwrap();
+
+        let mut dst_image = fr::Image::new(dst_width, dst_height, src_image.pixel_type());
+        let mut dst_view = dst_image.view_mut();
+        resizer.resize(&src_image.view(), &mut dst_view).unwrap();
+        alpha_mul_div.divide_alpha_inplace(&mut dst_view).unwrap();
+        dst_image.into_vec()
+    };
+
+    DynamicImage::ImageRgba8(image::RgbaImage::from_raw(size, size, rgba).unwrap())
 }
 
 pub fn extract_dir<P: AsRef<Path>>(
@@ -370,38 +1110,92 @@ pub fn extract_dir<P: AsRef<Path>>(
     Ok(())
 }
 
-pub fn spawn_threads<P: AsRef<Path>>(
-    output: P,
+/// Writes the bundled viewer through `sink`, like [`extract_dir`]
+fn write_web(dir: &Dir, sink: &dyn OutputSink, find_replace: &HashMap<String, String>) {
+    for entry in dir.entries() {
+        match entry {
+            include_dir::DirEntry::Dir(d) => write_web(d, sink, find_replace),
+            include_dir::DirEntry::File(f) => {
+                if let Some(utf8) = f.contents_utf8() {
+                    let mut contents = utf8.to_owned();
+                    for (find, replace) in find_replace.iter() {
+                        contents = contents.replace(find, replace);
+                    }
+                    sink.write_file(f.path(), contents.as_bytes());
+                } else {
+                    sink.write_file(f.path(), f.contents());
+                }
+            }
+        }
+    }
+}
+
+pub fn spawn_threads(
+    sink: Arc<dyn OutputSink>,
+    config: &RenderConfig,
+    encoder: Arc<dyn TileEncoder>,
     scope: &Scope,
-    recv_work: Receiver<MessageToWorker>,
+    recv_encode: Receiver<MessageToWorker>,
+    recv_resize: Receiver<MessageToWorker>,
     send_result: Sender<MessageToMain>,
 ) {
-    for _ in 0..std::thread::available_parallelism().unwrap().into() {
+    // an empty stage or queue never drains and the render waits forever
+    assert!(
+        config.queue_depth > 0
+            && config.encode_threads != Some(0)
+            && config.resize_threads != Some(0),
+        "queue depth and thread counts must be at least 1"
+    );
+    let threads =
+        |n: Option<usize>| n.unwrap_or_else(|| std::thread::available_parallelism().unwrap().into());
+    let stages = [
+        (recv_encode, threads(config.encode_threads)),
+        (recv_resize, threads(config.resize_threads)),
+    ];
+    for (recv_work, count) in stages {
+        spawn_stage(&sink, config, &encoder, scope, recv_work, count, &send_result);
+    }
+}
+
+fn spawn_stage(
+    sink: &Arc<dyn OutputSink>,
+    config: &RenderConfig,
+    encoder: &Arc<dyn TileEncoder>,
+    scope: &Scope,
+    recv_work: Receiver<MessageToWorker>,
+    count: usize,
+    send_result: &Sender<MessageToMain>,
+) {
+    for _ in 0..count {
         let recv_work = recv_work.clone();
         let send_result = send_result.clone();
-        let output = output.as_ref().to_owned();
+        let sink = sink.clone();
+        let config = config.clone();
+        let encoder = encoder.clone();
         scope.spawn(move |_| {
             while let Ok(work) = recv_work.recv() {
                 match work {
                     MessageToWorker::TileWriteParts { tile, image } => {
-                        tile_write_parts(&output, &tile, &image);
+                        tile_write_parts(&*sink, &config, &*encoder, &tile, &image);
                         send_result
                             .send(MessageToMain::FinishWriteParts { tile, image })
                             .unwrap();
                     }
                     MessageToWorker::TileBuildParent { parent, children } => {
-                        let mut full_size = DynamicImage::new_rgba8(TILE_SIZE * 2, TILE_SIZE * 2);
+                        let tile_size = config.tile_size;
+                        let mut full_size = DynamicImage::new_rgba8(tile_size * 2, tile_size * 2);
                         for (tile, img) in children {
+                            let img = img.load(&*sink, &config);
                             full_size
                                 .copy_from(
                                     &img,
-                                    (tile.x - parent.x * 2) as u32 * TILE_SIZE,
-                                    (tile.y - parent.y * 2) as u32 * TILE_SIZE,
+                                    (tile.x - parent.x * 2) as u32 * tile_size,
+                                    (tile.y - parent.y * 2) as u32 * tile_size,
                                 )
                                 .unwrap();
                         }
 
-                        let image = image_resize(full_size);
+                        let image = image_resize(full_size, tile_size, &config);
 
                         send_result
                             .send(MessageToMain::FinishBuildParent { parent, image })
@@ -413,13 +1207,189 @@ pub fn spawn_threads<P: AsRef<Path>>(
     }
 }
 
+/// Hands the children of `parent` to a resize worker once none of them are still waiting
+fn build_parent_if_ready(
+    tc: &mut ThreadContext,
+    parent: Tile,
+    send_resize: &Sender<MessageToWorker>,
+) {
+    if parent.zoom <= tc.min_zoom[&parent.surface] || !tc.tile_ready(&parent) {
+        return;
+    }
+    let mut children: Vec<(Tile, ChildImage)> = vec![];
+    for tile in parent.children().into_iter() {
+        if let Some(image) = tc.take(&tile) {
+            children.push((tile, image));
+        }
+    }
+
+    send_resize
+        .send(MessageToWorker::TileBuildParent { parent, children })
+        .unwrap();
+}
+
+/// Writes the viewer and the manifest once every tile is done
+fn finish(
+    tc: &mut ThreadContext,
+    output: &Path,
+    encoder: &dyn TileEncoder,
+    sink: &dyn OutputSink,
+) {
+    tc.progress.finish();
+    println!(
+        "Peak memory of pending tiles: {}, tiles spilled to disk: {}",
+        indicatif::HumanBytes(tc.peak_bytes as u64),
+        tc.spilled_tiles
+    );
+    fs::remove_dir_all(&tc.spill_dir).ok();
+    fs::remove_file(Journal::path(output)).ok();
+
+    let manifest = Manifest {
+        config: tc.config.clone(),
+        surfaces: tc
+            .info
+            .iter()
+            .filter_map(|s| {
+                let min_zoom = *tc.min_zoom.get(&s.name)?;
+                let chunks = s.chunks.iter().map(|c| (c.key(), c.hash.clone())).collect();
+                Some((s.name.to_owned(), SurfaceManifest { min_zoom, chunks }))
+            })
+            .collect(),
+    };
+    sink.write_file(
+        Path::new(Manifest::FILE),
+        &serde_json::to_vec(&manifest).unwrap(),
+    );
+
+    #[derive(Serialize)]
+    struct MapInfo {
+        surfaces: HashMap<String, Surface>,
+        extension: &'static str,
+        layout: TileLayout,
+        background: Rgb,
+        tile_size: u32,
+        part_size: u32,
+        max_zoom: i32,
+        zoom_range: ZoomRange,
+    }
+
+    #[derive(Serialize)]
+    struct Surface {
+        surface: String,
+        variant: Option<String>,
+        kind: Option<SurfaceKind>,
+        label: String,
+        tiles: Vec<(i32, i32, i32)>,
+        tags: HashMap<String, Vec<Tag>>,
+        grid: Option<SurfaceGrid>,
+        pruning_overlay: Option<PruningOverlay>,
+    }
+
+    let mut surfaces: HashMap<String, Surface> = std::mem::take(&mut tc.info)
+        .into_iter()
+        .map(|s| {
+            (
+                s.name.clone(),
+                Surface {
+                    label: s.label.unwrap_or_else(|| s.surface.clone()),
+                    surface: s.surface,
+                    variant: s.variant,
+                    kind: s.kind,
+                    tiles: Default::default(),
+                    tags: s.tags,
+                    grid: tc.grids.get(&s.name).cloned(),
+                    pruning_overlay: None,
+                },
+            )
+        })
+        .collect();
+    for tile in tc.tiles.keys() {
+        surfaces.get_mut(&tile.surface)
+            .unwrap()
+            .tiles
+            .extend(
+                get_tile_parts(&tc.config)
+                    .iter()
+                    .map(|p| p.get_path_components(&tc.config, tile)),
+            );
+    }
+
+    // the overlay is only a debugging aid, a render without the facts is still usable
+    let facts = tc.config.debug_pruning.then(|| {
+        let path = output.join(crate::prune::FACTS_FILE);
+        let facts = fs::read(&path).map_err(|e| e.to_string()).and_then(|data| {
+            serde_json::from_slice::<Vec<SurfaceFacts>>(&data).map_err(|e| e.to_string())
+        });
+        facts
+            .inspect_err(|e| {
+                println!(
+                    "Skipping the pruning overlay, could not read {}: {e}",
+                    path.display()
+                )
+            })
+            .ok()
+    });
+    if let Some(facts) = facts.flatten() {
+        // variants of a surface share its overlay
+        let overlays: HashMap<&str, PruningOverlay> = facts
+            .iter()
+            .filter_map(|f| {
+                let overlay = crate::overlay::write_pruning_overlay(sink, &tc.config, f)?;
+                Some((&f.name[..], overlay))
+            })
+            .collect();
+        for surface in surfaces.values_mut() {
+            surface.pruning_overlay = overlays.get(&surface.surface[..]).cloned();
+        }
+    }
+
+    for &kind in &tc.config.exports {
+        for (name, surface) in &surfaces {
+            crate::export::export(
+                kind,
+                sink,
+                &tc.config,
+                encoder.extension(),
+                name,
+                &surface.tiles,
+            );
+        }
+    }
+    let layout = sink.finish();
+
+    let info = MapInfo {
+        surfaces,
+        extension: encoder.extension(),
+        layout,
+        background: tc.config.matte,
+        tile_size: tc.config.tile_size,
+        part_size: tc.config.part_size(),
+        max_zoom: tc.config.max_zoom,
+        zoom_range: tc.config.zoom_range,
+    };
+
+    let mut find_replace = HashMap::new();
+    find_replace.insert(
+        "$MAP_DATA$".to_owned(),
+        serde_json::to_string(&info).unwrap(),
+    );
+    write_web(&WEB, sink, &find_replace);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn main_loop<P: AsRef<Path>>(
     output: P,
+    config: &RenderConfig,
+    encoder: &dyn TileEncoder,
+    sink: &dyn OutputSink,
     recv_result: Receiver<MessageToMain>,
-    send_work: Sender<MessageToWorker>,
+    send_encode: Sender<MessageToWorker>,
+    send_resize: Sender<MessageToWorker>,
     send_result: Sender<MessageToMain>,
 ) {
+    let output = output.as_ref();
     let mut thread_context = None;
+    let mut journal = None;
 
     while let Ok(status) = recv_result.recv() {
         match status {
@@ -431,84 +1401,68 @@ pub fn main_loop<P: AsRef<Path>>(
                 println!("finished");
                 break;
             }
+            MessageToMain::Failed(reason) => {
+                println!("failed: {reason}");
+                break;
+            }
             MessageToMain::File(file) => {
                 if file.path.file_name() == Some(std::ffi::OsStr::new("info.json")) {
                     let info_exists = thread_context.is_none();
                     assert!(info_exists, "SurfaceInfo already exists");
-                    let info = serde_json::from_slice(&file.data).unwrap();
-                    thread_context = Some(ThreadContext::new(info));
+                    let info = split_variants(serde_json::from_slice(&file.data).unwrap(), config);
+                    let previous = config
+                        .incremental
+                        .then(|| Manifest::read_compatible(output, config))
+                        .flatten();
+                    let finished = config
+                        .resume
+                        .then(|| Journal::read(output, config))
+                        .flatten();
+                    journal = Some(Journal::open(output, config, finished.is_some()));
+                    let tc = thread_context.insert(ThreadContext::new(
+                        info,
+                        config,
+                        output.join(".spill"),
+                        previous.as_ref(),
+                        &finished.unwrap_or_default(),
+                    ));
+                    sink.begin(&tc.grids);
+
+                    // parents of removed chunks may have no child left to render
+                    let waiting: Vec<Tile> = tc
+                        .tiles
+                        .iter()
+                        .filter(|(_, state)| matches!(state, TileState::Waiting))
+                        .map(|(tile, _)| tile.clone())
+                        .collect();
+                    for tile in waiting {
+                        if tile.zoom < config.max_zoom {
+                            build_parent_if_ready(tc, tile, &send_resize);
+                        }
+                    }
+
+                    if tc.total_tiles == 0 {
+                        finish(tc, output, encoder, sink);
+                        send_result.send(MessageToMain::Finished).unwrap();
+                    }
                 }
             }
             MessageToMain::FinishWriteParts { tile, image } => {
                 let tc = thread_context.as_mut().unwrap();
                 tc.progress();
+                journal.as_mut().unwrap().record(&tile);
 
-                tc.tiles.insert(tile.clone(), TileState::Loaded(image));
-
-                let parent = tile.zoom_out();
-                if parent.zoom > tc.min_zoom[&tile.surface] && tc.tile_ready(&parent) {
-                    let mut children: Vec<(Tile, DynamicImage)> = vec![];
-                    for tile in parent.children().into_iter() {
-                        if let Some(state) = tc.tiles.get_mut(&tile) {
-                            children.push((tile.clone(), state.take()));
-                        }
-                    }
-
-                    send_work
-                        .send(MessageToWorker::TileBuildParent { parent, children })
-                        .unwrap();
-                }
+                tc.insert_loaded(tile.clone(), image);
+                build_parent_if_ready(tc, tile.zoom_out(), &send_resize);
+                tc.spill();
 
                 if tc.loaded_tiles == tc.total_tiles {
-                    tc.progress.finish();
-                    #[derive(Serialize)]
-                    struct MapInfo {
-                        surfaces: HashMap<String, Surface>,
-                        extension: &'static str,
-                    }
-
-                    #[derive(Serialize)]
-                    struct Surface {
-                        tiles: Vec<(i32, i32, i32)>,
-                        tags: HashMap<String, Vec<Tag>>,
-                    }
-
-                    let mut surfaces: HashMap<String, Surface> = std::mem::take(&mut tc.info)
-                        .into_iter()
-                        .map(|s| {
-                            (
-                                s.name,
-                                Surface {
-                                    tiles: Default::default(),
-                                    tags: s.tags,
-                                },
-                            )
-                        })
-                        .collect();
-                    for tile in tc.tiles.keys() {
-                        surfaces.get_mut(&tile.surface)
-                            .unwrap()
-                            .tiles
-                            .extend(get_tile_parts().iter().map(|p| p.get_path_components(tile)));
-                    }
-
-                    let info = MapInfo {
-                        surfaces,
-                        extension: TILE_EXTENSION,
-                    };
-
-                    let mut find_replace = HashMap::new();
-                    find_replace.insert(
-                        "$MAP_DATA$".to_owned(),
-                        serde_json::to_string(&info).unwrap(),
-                    );
-                    extract_dir(&WEB, &output, &find_replace).unwrap();
-
+                    finish(tc, output, encoder, sink);
                     send_result.send(MessageToMain::Finished).unwrap();
                 }
             }
             MessageToMain::FinishBuildParent { parent, image } => {
-                send_work
+                send_encode
                     .send(MessageToWorker::TileWriteParts {
                         tile: parent,
                         image,
@@ -518,3 +1472,108 @@ pub fn main_loop<P: AsRef<Path>>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incremental_dirty_tiles() {
+        let config = RenderConfig {
+            tile_size: 64,
+            zoom_range: ZoomRange::MinZoom(18),
+            ..Default::default()
+        };
+        let info = serde_json::json!([
+            {
+                "name": "nauvis",
+                "tags": {},
+                "chunks": [
+                    {"x": 0, "y": 0, "hash": "a"},
+                    {"x": 1, "y": 0, "hash": "b2"},
+                    {"x": 4, "y": 0, "hash": "c"},
+                ],
+            },
+            {
+                "name": "vulcanus",
+                "tags": {},
+                "chunks": [{"x": 0, "y": 0, "hash": "x"}],
+            },
+        ]);
+        let info = split_variants(serde_json::from_value(info).unwrap(), &config);
+        let chunks = |chunks: &[(&str, &str)]| {
+            chunks
+                .iter()
+                .map(|&(key, hash)| (key.to_owned(), Some(hash.to_owned())))
+                .collect()
+        };
+        let previous = Manifest {
+            config: config.clone(),
+            surfaces: HashMap::from([
+                (
+                    "nauvis".to_owned(),
+                    SurfaceManifest {
+                        min_zoom: 17,
+                        // 1,0 changed and 5,0 was removed since
+                        chunks: chunks(&[("0,0", "a"), ("1,0", "b"), ("4,0", "c"), ("5,0", "d")]),
+                    },
+                ),
+                (
+                    "vulcanus".to_owned(),
+                    // the previous pyramid stopped above zoom 18
+                    SurfaceManifest {
+                        min_zoom: 18,
+                        chunks: chunks(&[("0,0", "x")]),
+                    },
+                ),
+            ]),
+        };
+        let tc = ThreadContext::new(
+            info,
+            &config,
+            PathBuf::new(),
+            Some(&previous),
+            &HashSet::new(),
+        );
+
+        let state = |surface: &str, zoom, x, y| {
+            let tile = Tile {
+                surface: surface.to_owned(),
+                zoom,
+                x,
+                y,
+            };
+            format!("{:?}", tc.tiles[&tile])
+        };
+        // ancestors of the changed chunk and of the removed one
+        assert_eq!(state("nauvis", 20, 1, 0), "Waiting");
+        assert_eq!(state("nauvis", 19, 0, 0), "Waiting");
+        assert_eq!(state("nauvis", 19, 2, 0), "Waiting");
+        assert_eq!(state("nauvis", 18, 0, 0), "Waiting");
+        assert_eq!(state("nauvis", 18, 1, 0), "Waiting");
+        // unchanged children of rebuilt parents are read back from disk
+        assert_eq!(state("nauvis", 20, 0, 0), "Existing");
+        assert_eq!(state("nauvis", 20, 4, 0), "Existing");
+        // only the zoom level the previous render didn't build is new
+        assert_eq!(state("vulcanus", 18, 0, 0), "Waiting");
+        assert_eq!(state("vulcanus", 19, 0, 0), "Existing");
+        assert_eq!(state("vulcanus", 20, 0, 0), "Processed");
+        assert_eq!(tc.total_tiles, 6);
+        assert_eq!(tc.tiles.len(), 10);
+    }
+
+    #[test]
+    fn linear_round_trip() {
+        let src: Vec<u8> = (0..=255u8).flat_map(|c| [c, c, c, 255]).collect();
+        let linear = srgb_to_linear_premultiplied(&src);
+        assert_eq!(linear_premultiplied_to_srgb(&linear), src);
+    }
+
+    #[test]
+    fn linear_transparent_stays_zero() {
+        let src = [255, 128, 0, 0, 10, 20, 30, 0];
+        let linear = srgb_to_linear_premultiplied(&src);
+        assert!(linear.iter().all(|&b| b == 0));
+        assert_eq!(linear_premultiplied_to_srgb(&linear), [0; 8]);
+    }
+}
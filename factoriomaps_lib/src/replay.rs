@@ -0,0 +1,186 @@
+use std::fs;
+use std::path::Path;
+
+use crossbeam::channel::{bounded, unbounded};
+
+use crate::export;
+use crate::prune::FACTS_FILE;
+use crate::render::{self, MessageToMain, MessageToWorker, RenderConfig, VirtualFile};
+
+/// Builds a map in `output` from the `info.json` and screenshots in `input` without Factorio
+///
+/// Screenshots are named like the mod names them, `<surface>,<x>,<y>[,<variant>]`, as `.bmp`
+/// or `.png` and go through the same pipeline as the ones intercepted from Factorio. A
+/// `chunks.json` next to them is used for the pruning overlay.
+pub fn replay(input: &Path, output: &Path, config: &RenderConfig) {
+    assert!(
+        !config.incremental && !config.resume,
+        "replays always render every chunk, incremental and resume are not supported"
+    );
+    export::validate(config).unwrap_or_else(|e| panic!("iiif_base_url {e}"));
+    let info = fs::read(input.join("info.json")).expect("no info.json in input");
+
+    // the pipeline waits for every chunk, so check they are all there before starting
+    let screenshots: Vec<_> = render::screenshot_names(&info, config)
+        .into_iter()
+        .map(|(tile, name)| {
+            let path = ["bmp", "png"]
+                .iter()
+                .map(|ext| input.join(format!("{name}.{ext}")))
+                .find(|path| path.exists())
+                .unwrap_or_else(|| panic!("missing screenshot {name}.bmp or {name}.png"));
+            (tile, path)
+        })
+        .collect();
+
+    fs::create_dir_all(output).unwrap();
+    let facts = input.join(FACTS_FILE);
+    if facts.exists() {
+        fs::copy(&facts, output.join(FACTS_FILE)).unwrap();
+    } else {
+        assert!(
+            !config.debug_pruning,
+            "--debug-pruning needs the {FACTS_FILE} of the render in input"
+        );
+    }
+
+    let (result_tx, result_rx) = unbounded::<MessageToMain>();
+    let (work_tx, work_rx) = bounded::<MessageToWorker>(config.queue_depth);
+    let (resize_tx, resize_rx) = bounded::<MessageToWorker>(config.queue_depth);
+
+    let encoder = config.encoder();
+    let sink = config.sink.sink(output, config, encoder.extension());
+    crossbeam::scope(|scope| {
+        render::spawn_threads(
+            sink.clone(),
+            config,
+            encoder.clone(),
+            scope,
+            work_rx,
+            resize_rx,
+            result_tx.clone(),
+        );
+
+        // info.json is queued before any screenshot like when Factorio writes it
+        result_tx
+            .send(MessageToMain::File(VirtualFile {
+                path: "info.json".into(),
+                data: info,
+            }))
+            .unwrap();
+        let work = work_tx.clone();
+        scope.spawn(move |_| {
+            for (tile, path) in screenshots {
+                let image = image::open(&path).unwrap().into_rgba8();
+                assert!(
+                    image.width() == config.tile_size && image.height() == config.tile_size,
+                    "{} is {}x{}, expected the tile size {}",
+                    path.display(),
+                    image.width(),
+                    image.height(),
+                    config.tile_size
+                );
+                let image = image::DynamicImage::ImageRgba8(image);
+                work.send(MessageToWorker::TileWriteParts { tile, image })
+                    .unwrap();
+            }
+        });
+
+        render::main_loop(
+            output, config, &*encoder, &*sink, result_rx, work_tx, resize_tx, result_tx,
+        );
+    })
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::render::{TileFormat, ZoomRange};
+
+    #[test]
+    fn replay_synthetic_screenshots() {
+        let dir = std::env::temp_dir().join(format!("fbrs-replay-{}", std::process::id()));
+        let (input, output) = (dir.join("input"), dir.join("output"));
+        fs::create_dir_all(&input).unwrap();
+        let info = serde_json::json!([{
+            "name": "nauvis",
+            "tags": {},
+            "chunks": [{"x": 0, "y": 0}, {"x": 1, "y": 0}, {"x": -1, "y": -1}],
+        }]);
+        fs::write(input.join("info.json"), info.to_string()).unwrap();
+        let chunks = [
+            (0, 0, [255, 0, 0]),
+            (1, 0, [0, 0, 255]),
+            (-1, -1, [0, 255, 0]),
+        ];
+        for (x, y, [r, g, b]) in chunks {
+            image::RgbaImage::from_pixel(64, 64, image::Rgba([r, g, b, 255]))
+                .save(input.join(format!("nauvis,{x},{y}.png")))
+                .unwrap();
+        }
+
+        let config = RenderConfig {
+            tile_size: 64,
+            zoom_range: ZoomRange::MinZoom(19),
+            format: TileFormat::Png,
+            encode_threads: Some(2),
+            resize_threads: Some(2),
+            ..Default::default()
+        };
+        replay(&input, &output, &config);
+
+        // every tile is split into 2x2 parts, chunks 0,0 and 1,0 share their parent
+        let tiles = [
+            (20, 0, 0),
+            (20, 1, 0),
+            (20, -1, -1),
+            (19, 0, 0),
+            (19, -1, -1),
+        ];
+        let expected: HashSet<(i32, i32, i32)> = tiles
+            .iter()
+            .flat_map(|&(z, x, y)| {
+                [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(px, py)| (z, x * 2 + px, y * 2 + py))
+            })
+            .collect();
+        for &(z, x, y) in &expected {
+            let path = output.join(format!("tiles/nauvis/{z}/{x}/{y}.png"));
+            assert!(path.exists(), "missing part {}", path.display());
+        }
+
+        // the parent is downscaled from its children, the half without a chunk is matte
+        let parent = |x: i32, y: i32| {
+            image::open(output.join(format!("tiles/nauvis/19/{x}/{y}.png")))
+                .unwrap()
+                .into_rgb8()
+        };
+        let close =
+            |a: &image::Rgb<u8>, b: [u8; 3]| a.0.iter().zip(b).all(|(&a, b)| a.abs_diff(b) <= 2);
+        assert!(close(parent(0, 0).get_pixel(16, 16), [255, 0, 0]));
+        assert!(close(parent(1, 0).get_pixel(16, 16), [0, 0, 255]));
+        assert!(close(parent(0, 1).get_pixel(16, 16), config.matte.0));
+
+        let html = fs::read_to_string(output.join("index.html")).unwrap();
+        let map_info = html
+            .split_once("window.mapInfo = ")
+            .and_then(|(_, rest)| rest.split_once(";</script>"))
+            .unwrap()
+            .0;
+        let map_info: serde_json::Value = serde_json::from_str(map_info).unwrap();
+        let listed: HashSet<(i32, i32, i32)> =
+            serde_json::from_value(map_info["surfaces"]["nauvis"]["tiles"].clone()).unwrap();
+        assert_eq!(listed, expected);
+
+        let manifest = render::Manifest::read(&output).unwrap();
+        let surface = &manifest.surfaces["nauvis"];
+        let mut keys: Vec<&str> = surface.chunks.keys().map(|k| &k[..]).collect();
+        keys.sort();
+        assert_eq!(keys, ["-1,-1", "0,0", "1,0"]);
+        assert_eq!(surface.min_zoom, 18);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
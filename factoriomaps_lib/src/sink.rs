@@ -0,0 +1,1000 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::render::RenderConfig;
+
+/// Where finished tile parts, the manifest and the viewer end up
+///
+/// Parts are addressed by surface and the zoom/x/y the viewer requests them with.
+pub trait OutputSink: Send + Sync {
+    /// Called once the extent of every surface is known, parts may be written before that
+    fn begin(&self, _grids: &HashMap<String, SurfaceGrid>) {}
+    fn write_part(&self, surface: &str, zoom: i32, x: i32, y: i32, data: Vec<u8>);
+    /// Returns a part written by a previous render. Sinks that can't read back don't support
+    /// incremental and resumed renders
+    fn read_part(&self, _surface: &str, _zoom: i32, _x: i32, _y: i32) -> Option<Vec<u8>> {
+        None
+    }
+    /// Stores a file outside of the tile pyramid such as the manifest or a viewer asset
+    fn write_file(&self, path: &Path, data: &[u8]);
+    /// Stores a part written by a previous render as a file like `write_file`, returns false
+    /// if there is no such part
+    fn export_part(&self, surface: &str, zoom: i32, x: i32, y: i32, path: &Path) -> bool {
+        let Some(data) = self.read_part(surface, zoom, x, y) else {
+            return false;
+        };
+        self.write_file(path, &data);
+        true
+    }
+    /// Called once every part has been written, returns how the viewer finds the parts
+    fn finish(&self) -> TileLayout;
+}
+
+/// Tells the viewer how to load parts, stored in the map info
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum TileLayout {
+    /// `tiles/` followed by the path scheme
+    Directory { scheme: PathScheme },
+    /// `tiles/<surface>.pmtiles` with PMTiles zoom = zoom - offset and x/y shifted by half
+    /// the grid to be non-negative, see [`grid_zoom_offset`]
+    Pmtiles { zoom_offsets: HashMap<String, i32> },
+    /// `tiles/<surface>.mbtiles` with the same zoom offset and shift as PMTiles and TMS rows
+    Mbtiles { zoom_offsets: HashMap<String, i32> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    Directory,
+    Pmtiles,
+    Mbtiles,
+}
+impl SinkKind {
+    pub fn sink<P: AsRef<Path>>(
+        &self,
+        output: P,
+        config: &RenderConfig,
+        extension: &'static str,
+    ) -> Arc<dyn OutputSink> {
+        let output = output.as_ref().to_owned();
+        match self {
+            SinkKind::Directory => Arc::new(DirectorySink::new(
+                output,
+                extension,
+                config.path_scheme.clone(),
+            )),
+            SinkKind::Pmtiles => Arc::new(PmtilesSink {
+                output,
+                extension,
+                surfaces: Default::default(),
+            }),
+            SinkKind::Mbtiles => Arc::new(MbtilesSink {
+                output,
+                extension,
+                surfaces: Default::default(),
+            }),
+        }
+    }
+    /// Whether parts written by a previous render can be read back
+    pub fn readable(&self) -> bool {
+        matches!(self, SinkKind::Directory)
+    }
+}
+impl std::str::FromStr for SinkKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "directory" => Ok(SinkKind::Directory),
+            "pmtiles" => Ok(SinkKind::Pmtiles),
+            "mbtiles" => Ok(SinkKind::Mbtiles),
+            _ => Err(format!(
+                "unknown output sink \"{s}\", expected one of: directory, pmtiles, mbtiles"
+            )),
+        }
+    }
+}
+
+/// Extent of a surface in tiles at `max_zoom`, both ends inclusive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SurfaceGrid {
+    pub max_zoom: i32,
+    /// Lowest zoom level that is written
+    pub min_zoom: i32,
+    pub num_parts: i32,
+    pub min_x: i32,
+    pub min_y: i32,
+    pub max_x: i32,
+    pub max_y: i32,
+}
+impl SurfaceGrid {
+    /// Returns the extent of the chunks and the lowest zoom level their pyramid goes down to
+    pub fn new(
+        config: &RenderConfig,
+        chunks: impl IntoIterator<Item = (i32, i32)>,
+    ) -> Option<Self> {
+        let mut chunks = chunks.into_iter();
+        let (x, y) = chunks.next()?;
+        let (mut min_x, mut max_x, mut min_y, mut max_y) = (x, x, y, y);
+        for (x, y) in chunks {
+            min_x = min_x.min(x);
+            max_x = max_x.max(x);
+            min_y = min_y.min(y);
+            max_y = max_y.max(y)
+        }
+        Some(SurfaceGrid {
+            max_zoom: config.max_zoom,
+            min_zoom: config
+                .zoom_range
+                .min_zoom(config, (min_x, min_y), (max_x, max_y)),
+            num_parts: config.num_parts as i32,
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+        })
+    }
+}
+
+/// How the directory sink turns part coordinates into paths
+///
+/// The viewer applies the same transform in `partPath`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathScheme {
+    /// Path below `tiles/` with `{surface}`, `{z}`, `{x}`, `{y}` and `{ext}` placeholders
+    pub template: String,
+    /// Start zoom levels at 0 for the lowest zoom of each surface
+    pub rebase_zoom: bool,
+    /// Count rows upwards like TMS instead of downwards like XYZ
+    pub flip_y: bool,
+    /// Shift x and y so the top left part of each zoom level is 0, 0
+    pub non_negative: bool,
+}
+impl Default for PathScheme {
+    fn default() -> Self {
+        Self {
+            template: "{surface}/{z}/{x}/{y}.{ext}".to_owned(),
+            rebase_zoom: false,
+            flip_y: false,
+            non_negative: false,
+        }
+    }
+}
+impl PathScheme {
+    /// Whether paths depend on the extent of the surface
+    pub fn needs_grid(&self) -> bool {
+        self.rebase_zoom || self.non_negative
+    }
+    fn path(
+        &self,
+        grid: Option<&SurfaceGrid>,
+        surface: &str,
+        zoom: i32,
+        x: i32,
+        y: i32,
+        extension: &str,
+    ) -> String {
+        let (mut z, mut x, mut y) = (zoom, x, y);
+        if let Some(grid) = grid {
+            let scale = 1 << (grid.max_zoom - zoom);
+            if self.non_negative {
+                x -= grid.min_x.div_floor(scale) * grid.num_parts;
+                y = if self.flip_y {
+                    (grid.max_y.div_floor(scale) + 1) * grid.num_parts - 1 - y
+                } else {
+                    y - grid.min_y.div_floor(scale) * grid.num_parts
+                };
+            } else if self.flip_y {
+                y = -1 - y;
+            }
+            if self.rebase_zoom {
+                z -= grid.min_zoom;
+            }
+        } else if self.flip_y {
+            y = -1 - y;
+        }
+        self.template
+            .replace("{surface}", surface)
+            .replace("{z}", &z.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &y.to_string())
+            .replace("{ext}", extension)
+    }
+}
+
+fn write_output_file(output: &Path, path: &Path, data: &[u8]) {
+    let path = output.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, data).unwrap();
+}
+
+/// How long [`DirectorySink`] waits for the extent of the surfaces
+const GRID_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// One file per part, the layout the bundled viewer reads by default
+pub struct DirectorySink {
+    pub output: PathBuf,
+    pub extension: &'static str,
+    pub scheme: PathScheme,
+    /// Set by `begin`, writers wait for it if the scheme needs it
+    ///
+    /// The mod writes `info.json` before requesting any screenshot, so the wait only lasts
+    /// until the main loop has read it. Writers give up after [`GRID_TIMEOUT`] so a render that
+    /// never gets there fails instead of hanging.
+    grids: Mutex<Option<HashMap<String, SurfaceGrid>>>,
+    grids_set: Condvar,
+}
+impl DirectorySink {
+    pub fn new(output: PathBuf, extension: &'static str, scheme: PathScheme) -> Self {
+        Self {
+            output,
+            extension,
+            scheme,
+            grids: Mutex::new(None),
+            grids_set: Condvar::new(),
+        }
+    }
+    fn part_path(&self, surface: &str, zoom: i32, x: i32, y: i32) -> PathBuf {
+        let path = if self.scheme.needs_grid() {
+            let (grids, timeout) = self
+                .grids_set
+                .wait_timeout_while(self.grids.lock().unwrap(), GRID_TIMEOUT, |g| g.is_none())
+                .unwrap();
+            assert!(
+                !timeout.timed_out(),
+                "no info.json within {GRID_TIMEOUT:?} of the first tile, can't lay out tiles"
+            );
+            let grid = grids.as_ref().unwrap().get(surface);
+            self.scheme.path(grid, surface, zoom, x, y, self.extension)
+        } else {
+            self.scheme.path(None, surface, zoom, x, y, self.extension)
+        };
+        self.output.join("tiles").join(path)
+    }
+}
+impl OutputSink for DirectorySink {
+    fn begin(&self, grids: &HashMap<String, SurfaceGrid>) {
+        *self.grids.lock().unwrap() = Some(grids.clone());
+        self.grids_set.notify_all();
+    }
+    fn write_part(&self, surface: &str, zoom: i32, x: i32, y: i32, data: Vec<u8>) {
+        let path = self.part_path(surface, zoom, x, y);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+    fn read_part(&self, surface: &str, zoom: i32, x: i32, y: i32) -> Option<Vec<u8>> {
+        fs::read(self.part_path(surface, zoom, x, y)).ok()
+    }
+    /// Hard links the part so exports don't take up space again, copies across filesystems
+    fn export_part(&self, surface: &str, zoom: i32, x: i32, y: i32, path: &Path) -> bool {
+        let part = self.part_path(surface, zoom, x, y);
+        if !part.is_file() {
+            return false;
+        }
+        let path = self.output.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::remove_file(&path).ok();
+        if fs::hard_link(&part, &path).is_err() {
+            fs::copy(&part, &path).unwrap();
+        }
+        true
+    }
+    fn write_file(&self, path: &Path, data: &[u8]) {
+        write_output_file(&self.output, path, data)
+    }
+    fn finish(&self) -> TileLayout {
+        TileLayout::Directory {
+            scheme: self.scheme.clone(),
+        }
+    }
+}
+
+/// One PMTiles v3 archive per surface which the viewer reads with HTTP range requests
+///
+/// Part data is appended to a scratch file as it arrives and the directory is written when
+/// the render finishes.
+pub struct PmtilesSink {
+    pub output: PathBuf,
+    pub extension: &'static str,
+    surfaces: Mutex<HashMap<String, PmtilesSurface>>,
+}
+struct PmtilesSurface {
+    data: fs::File,
+    data_path: PathBuf,
+    data_len: u64,
+    /// zoom, x, y, offset, length
+    parts: Vec<(i32, i32, i32, u64, u32)>,
+}
+impl PmtilesSink {
+    fn scratch_dir(&self) -> PathBuf {
+        self.output.join(".pmtiles")
+    }
+    fn tile_type(&self) -> u8 {
+        match self.extension {
+            "png" => 2,
+            "jpg" => 3,
+            "webp" => 4,
+            _ => 0,
+        }
+    }
+    fn write_archive(&self, name: &str, surface: PmtilesSurface) -> i32 {
+        let PmtilesSurface {
+            data,
+            data_path,
+            data_len,
+            parts,
+        } = surface;
+        drop(data);
+
+        let coordinates: Vec<_> = parts.iter().map(|p| (p.0, p.1, p.2)).collect();
+        let zoom_offset = grid_zoom_offset(&coordinates);
+        let min_zoom = parts.iter().map(|p| p.0).min().unwrap() - zoom_offset;
+
+        let mut entries: Vec<Entry> = parts
+            .iter()
+            .map(|&(zoom, x, y, offset, length)| {
+                let z = zoom - zoom_offset;
+                assert!(z <= 31, "surface {name} is too large for a PMTiles archive");
+                let half = 1i64 << (z - 1);
+                Entry {
+                    tile_id: zxy_to_tile_id(z as u8, (x as i64 + half) as u32, (y as i64 + half) as u32),
+                    offset,
+                    length,
+                    run_length: 1,
+                }
+            })
+            .collect();
+        entries.sort_by_key(|e| e.tile_id);
+
+        let (root, leaves) = build_directories(&entries);
+        let metadata = serde_json::to_vec(&serde_json::json!({ "name": name })).unwrap();
+        let max_zoom = parts.iter().map(|p| p.0).max().unwrap() - zoom_offset;
+
+        let root_offset = HEADER_SIZE as u64;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaves_offset = metadata_offset + metadata.len() as u64;
+        let data_offset = leaves_offset + leaves.len() as u64;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        for n in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            metadata.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            data_offset,
+            data_len,
+            entries.len() as u64,
+            entries.len() as u64,
+            entries.len() as u64,
+        ] {
+            header.extend_from_slice(&n.to_le_bytes());
+        }
+        header.push(0); // not clustered
+        header.push(1); // directories uncompressed
+        header.push(1); // tiles uncompressed
+        header.push(self.tile_type());
+        header.push(min_zoom as u8);
+        header.push(max_zoom as u8);
+        // bounds and center are meaningless for a game map so cover the whole world
+        for n in [-1_800_000_000i32, -850_000_000, 1_800_000_000, 850_000_000] {
+            header.extend_from_slice(&n.to_le_bytes());
+        }
+        header.push(min_zoom as u8);
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        assert_eq!(header.len(), HEADER_SIZE);
+
+        let path = self.output.join("tiles").join(format!("{name}.pmtiles"));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut archive = std::io::BufWriter::new(fs::File::create(path).unwrap());
+        archive.write_all(&header).unwrap();
+        archive.write_all(&root).unwrap();
+        archive.write_all(&metadata).unwrap();
+        archive.write_all(&leaves).unwrap();
+        std::io::copy(&mut fs::File::open(&data_path).unwrap(), &mut archive).unwrap();
+        archive.flush().unwrap();
+        fs::remove_file(data_path).unwrap();
+
+        zoom_offset
+    }
+}
+impl OutputSink for PmtilesSink {
+    fn write_part(&self, surface: &str, zoom: i32, x: i32, y: i32, data: Vec<u8>) {
+        let mut surfaces = self.surfaces.lock().unwrap();
+        let s = surfaces.entry(surface.to_owned()).or_insert_with(|| {
+            let data_path = self.scratch_dir().join(surface);
+            fs::create_dir_all(data_path.parent().unwrap()).unwrap();
+            PmtilesSurface {
+                data: fs::File::create(&data_path).unwrap(),
+                data_path,
+                data_len: 0,
+                parts: vec![],
+            }
+        });
+        s.data.write_all(&data).unwrap();
+        s.parts.push((zoom, x, y, s.data_len, data.len() as u32));
+        s.data_len += data.len() as u64;
+    }
+    fn write_file(&self, path: &Path, data: &[u8]) {
+        write_output_file(&self.output, path, data)
+    }
+    fn finish(&self) -> TileLayout {
+        let surfaces = std::mem::take(&mut *self.surfaces.lock().unwrap());
+        let zoom_offsets = surfaces
+            .into_iter()
+            .map(|(name, surface)| {
+                let offset = self.write_archive(&name, surface);
+                (name, offset)
+            })
+            .collect();
+        fs::remove_dir_all(self.scratch_dir()).ok();
+        TileLayout::Pmtiles { zoom_offsets }
+    }
+}
+
+/// One MBTiles tileset per surface, identical parts are stored once
+///
+/// Coordinates are stored unshifted while rendering and moved onto the TMS grid when the
+/// render finishes.
+pub struct MbtilesSink {
+    pub output: PathBuf,
+    pub extension: &'static str,
+    surfaces: Mutex<HashMap<String, MbtilesSurface>>,
+}
+struct MbtilesSurface {
+    db: rusqlite::Connection,
+    /// Hash of part data to the ids of images with that hash
+    images: HashMap<u64, Vec<i64>>,
+}
+impl MbtilesSink {
+    fn open(&self, surface: &str) -> MbtilesSurface {
+        let path = self.output.join("tiles").join(format!("{surface}.mbtiles"));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::remove_file(&path).ok();
+        let db = rusqlite::Connection::open(path).unwrap();
+        db.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE TABLE map (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_id INTEGER);
+             CREATE TABLE images (tile_id INTEGER PRIMARY KEY, tile_data BLOB);
+             CREATE VIEW tiles AS
+                 SELECT map.zoom_level AS zoom_level, map.tile_column AS tile_column,
+                     map.tile_row AS tile_row, images.tile_data AS tile_data
+                 FROM map JOIN images ON images.tile_id = map.tile_id;
+             BEGIN;",
+        )
+        .unwrap();
+        MbtilesSurface {
+            db,
+            images: HashMap::new(),
+        }
+    }
+    fn finish_surface(&self, name: &str, surface: MbtilesSurface) -> i32 {
+        let db = surface.db;
+        let parts: Vec<(i32, i32, i32)> = db
+            .prepare("SELECT zoom_level, tile_column, tile_row FROM map")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let zoom_offset = grid_zoom_offset(&parts);
+
+        // game y grows downwards like XYZ so TMS rows are flipped
+        db.execute(
+            "UPDATE map SET
+                 tile_column = tile_column + (1 << (zoom_level - ?1 - 1)),
+                 tile_row = (1 << (zoom_level - ?1)) - 1 - (tile_row + (1 << (zoom_level - ?1 - 1))),
+                 zoom_level = zoom_level - ?1",
+            [zoom_offset],
+        )
+        .unwrap();
+        db.execute_batch(
+            "CREATE UNIQUE INDEX map_index ON map (zoom_level, tile_column, tile_row);",
+        )
+        .unwrap();
+
+        let (min_x, min_y, max_x, max_y) = parts.iter().fold(
+            (i32::MAX, i32::MAX, i32::MIN, i32::MIN),
+            |(min_x, min_y, max_x, max_y), &(_, x, y)| {
+                (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+            },
+        );
+        let min_zoom = parts.iter().map(|p| p.0).min().unwrap();
+        let max_zoom = parts.iter().map(|p| p.0).max().unwrap();
+        let metadata = [
+            ("name", name.to_owned()),
+            ("format", self.extension.to_owned()),
+            ("type", "baselayer".to_owned()),
+            ("minzoom", (min_zoom - zoom_offset).to_string()),
+            ("maxzoom", (max_zoom - zoom_offset).to_string()),
+            // geographic bounds are meaningless for a game map so cover the whole world
+            ("bounds", "-180,-85,180,85".to_owned()),
+            ("zoom_offset", zoom_offset.to_string()),
+            // unshifted viewer coordinates of the parts across all zoom levels
+            (
+                "game_bounds",
+                format!("{min_x},{min_y},{max_x},{max_y}"),
+            ),
+        ];
+        for (key, value) in metadata {
+            db.execute(
+                "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+                [key, value.as_str()],
+            )
+            .unwrap();
+        }
+        db.execute_batch("COMMIT;").unwrap();
+
+        zoom_offset
+    }
+}
+impl OutputSink for MbtilesSink {
+    fn write_part(&self, surface: &str, zoom: i32, x: i32, y: i32, data: Vec<u8>) {
+        use std::hash::{Hash, Hasher};
+
+        let mut surfaces = self.surfaces.lock().unwrap();
+        let s = surfaces
+            .entry(surface.to_owned())
+            .or_insert_with(|| self.open(surface));
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        data.hash(&mut hasher);
+        let candidates = s.images.entry(hasher.finish()).or_default();
+        let existing = candidates.iter().copied().find(|&id| {
+            let stored: Vec<u8> = s
+                .db
+                .query_row("SELECT tile_data FROM images WHERE tile_id = ?1", [id], |r| r.get(0))
+                .unwrap();
+            stored == data
+        });
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                s.db.execute("INSERT INTO images (tile_data) VALUES (?1)", [&data])
+                    .unwrap();
+                let id = s.db.last_insert_rowid();
+                candidates.push(id);
+                id
+            }
+        };
+        s.db.execute(
+            "INSERT INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?1, ?2, ?3, ?4)",
+            [zoom as i64, x as i64, y as i64, id],
+        )
+        .unwrap();
+    }
+    fn write_file(&self, path: &Path, data: &[u8]) {
+        write_output_file(&self.output, path, data)
+    }
+    fn finish(&self) -> TileLayout {
+        let surfaces = std::mem::take(&mut *self.surfaces.lock().unwrap());
+        let zoom_offsets = surfaces
+            .into_iter()
+            .map(|(name, surface)| {
+                let offset = self.finish_surface(&name, surface);
+                (name, offset)
+            })
+            .collect();
+        TileLayout::Mbtiles { zoom_offsets }
+    }
+}
+
+/// Returns the offset to subtract from viewer zoom levels so that, after adding half the
+/// grid size, every x/y fits the non-negative `2^zoom` grid of web map tiles
+///
+/// The lowest zoom level is mapped to at least 1 so there is room on either side of the origin.
+fn grid_zoom_offset(parts: &[(i32, i32, i32)]) -> i32 {
+    let min_zoom = parts.iter().map(|p| p.0).min().unwrap();
+    let fits = |shift: i32| {
+        parts.iter().all(|&(zoom, x, y)| {
+            let half = 1i64 << (zoom - min_zoom + shift - 1);
+            (-half..half).contains(&(x as i64)) && (-half..half).contains(&(y as i64))
+        })
+    };
+    let shift = (1..).find(|&shift| fits(shift)).unwrap();
+    min_zoom - shift
+}
+
+const HEADER_SIZE: usize = 127;
+/// The header and root directory have to fit in the first 16 KiB
+const ROOT_SIZE: usize = 16384 - HEADER_SIZE;
+
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+/// Position of a tile on the Hilbert curve of its zoom level after all lower zoom levels
+fn zxy_to_tile_id(z: u8, mut x: u32, mut y: u32) -> u64 {
+    let mut id = ((1u64 << (z * 2)) - 1) / 3;
+    if z == 0 {
+        return id;
+    }
+    let mut s = 1u32 << (z - 1);
+    while s > 0 {
+        let rx = s & x;
+        let ry = s & y;
+        id += ((3 * rx as u64) ^ ry as u64) * s as u64;
+        if ry == 0 {
+            if rx != 0 {
+                x = (s - 1).wrapping_sub(x);
+                y = (s - 1).wrapping_sub(y);
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s >>= 1;
+    }
+    id
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut buf = vec![];
+    write_varint(&mut buf, entries.len() as u64);
+    let mut last_id = 0;
+    for e in entries {
+        write_varint(&mut buf, e.tile_id - last_id);
+        last_id = e.tile_id;
+    }
+    for e in entries {
+        write_varint(&mut buf, e.run_length as u64);
+    }
+    for e in entries {
+        write_varint(&mut buf, e.length as u64);
+    }
+    for (i, e) in entries.iter().enumerate() {
+        let contiguous = i > 0 && {
+            let prev = &entries[i - 1];
+            e.offset == prev.offset + prev.length as u64
+        };
+        write_varint(&mut buf, if contiguous { 0 } else { e.offset + 1 });
+    }
+    buf
+}
+
+/// Returns the root directory and leaf directories, splitting into leaves if the entries
+/// don't fit in the root
+fn build_directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>) {
+    let root = serialize_directory(entries);
+    if root.len() <= ROOT_SIZE {
+        return (root, vec![]);
+    }
+    let mut leaf_size = 4096;
+    loop {
+        let mut root_entries = vec![];
+        let mut leaves = vec![];
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk);
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend(leaf);
+        }
+        let root = serialize_directory(&root_entries);
+        if root.len() <= ROOT_SIZE {
+            return (root, leaves);
+        }
+        leaf_size *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_varint(buf: &mut &[u8]) -> u64 {
+        let mut n = 0;
+        let mut shift = 0;
+        loop {
+            let (&b, rest) = buf.split_first().unwrap();
+            *buf = rest;
+            n |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return n;
+            }
+            shift += 7;
+        }
+    }
+
+    /// tile id, offset, length and run length of every entry
+    fn deserialize_directory(mut buf: &[u8]) -> Vec<(u64, u64, u32, u32)> {
+        let buf = &mut buf;
+        let len = read_varint(buf) as usize;
+        let mut entries = vec![(0, 0, 0, 0); len];
+        let mut last_id = 0;
+        for e in &mut entries {
+            last_id += read_varint(buf);
+            e.0 = last_id;
+        }
+        for e in &mut entries {
+            e.3 = read_varint(buf) as u32;
+        }
+        for e in &mut entries {
+            e.2 = read_varint(buf) as u32;
+        }
+        for i in 0..len {
+            let offset = read_varint(buf);
+            entries[i].1 = if offset == 0 {
+                entries[i - 1].1 + entries[i - 1].2 as u64
+            } else {
+                offset - 1
+            };
+        }
+        assert!(buf.is_empty());
+        entries
+    }
+
+    fn grid() -> SurfaceGrid {
+        SurfaceGrid {
+            max_zoom: 20,
+            min_zoom: 17,
+            num_parts: 2,
+            min_x: -3,
+            min_y: -2,
+            max_x: 4,
+            max_y: 5,
+        }
+    }
+
+    #[test]
+    fn default_path() {
+        let scheme = PathScheme::default();
+        assert_eq!(
+            scheme.path(None, "nauvis", 20, -3, 4, "png"),
+            "nauvis/20/-3/4.png"
+        );
+        // the grid doesn't change plain XYZ paths
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 18, -1, 0, "jpg"),
+            "nauvis/18/-1/0.jpg"
+        );
+        let scheme = PathScheme {
+            template: "{z}-{x}-{y}.{ext}".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(scheme.path(None, "nauvis", 20, 1, 2, "png"), "20-1-2.png");
+    }
+
+    #[test]
+    fn rebase_zoom_path() {
+        let scheme = PathScheme {
+            rebase_zoom: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 20, -3, 4, "png"),
+            "nauvis/3/-3/4.png"
+        );
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 17, 0, 0, "png"),
+            "nauvis/0/0/0.png"
+        );
+    }
+
+    #[test]
+    fn flip_y_path() {
+        let scheme = PathScheme {
+            flip_y: true,
+            ..Default::default()
+        };
+        // rows are mirrored around the origin, row 0 is the first above it
+        assert_eq!(
+            scheme.path(None, "nauvis", 20, 0, 0, "png"),
+            "nauvis/20/0/-1.png"
+        );
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 20, 0, -4, "png"),
+            "nauvis/20/0/3.png"
+        );
+    }
+
+    #[test]
+    fn non_negative_path() {
+        let scheme = PathScheme {
+            non_negative: true,
+            ..Default::default()
+        };
+        // top left part of chunk -3, -2 at max zoom
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 20, -6, -4, "png"),
+            "nauvis/20/0/0.png"
+        );
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 20, 9, 11, "png"),
+            "nauvis/20/15/15.png"
+        );
+        // at zoom 18 the grid starts at tile -1, -1
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 18, -2, -2, "png"),
+            "nauvis/18/0/0.png"
+        );
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 18, 3, 3, "png"),
+            "nauvis/18/5/5.png"
+        );
+    }
+
+    #[test]
+    fn non_negative_flip_y_path() {
+        let scheme = PathScheme {
+            template: "{z}/{x}/{y}.{ext}".to_owned(),
+            rebase_zoom: true,
+            flip_y: true,
+            non_negative: true,
+        };
+        // bottom row is 0
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 20, -6, 11, "png"),
+            "3/0/0.png"
+        );
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 20, -6, -4, "png"),
+            "3/0/15.png"
+        );
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 18, -2, 3, "png"),
+            "1/0/0.png"
+        );
+        assert_eq!(
+            scheme.path(Some(&grid()), "nauvis", 18, -2, -2, "png"),
+            "1/0/5.png"
+        );
+    }
+
+    #[test]
+    fn tile_ids() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(12, 3423, 1763), 19078479);
+    }
+
+    #[test]
+    fn varints() {
+        for n in [0, 1, 127, 128, 300, 16384, u32::MAX as u64, u64::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, n);
+            let mut slice = &buf[..];
+            assert_eq!(read_varint(&mut slice), n);
+            assert!(slice.is_empty());
+        }
+        let mut buf = vec![];
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let entries = [
+            // contiguous with a run of three identical tiles
+            (1, 0, 10, 1),
+            (2, 10, 20, 3),
+            // gap in both ids and data
+            (40, 100, 5, 1),
+            (41, 105, 7, 2),
+        ]
+        .map(|(tile_id, offset, length, run_length)| Entry {
+            tile_id,
+            offset,
+            length,
+            run_length,
+        });
+        let (root, leaves) = build_directories(&entries);
+        assert!(leaves.is_empty());
+        let expected: Vec<_> = entries
+            .iter()
+            .map(|e| (e.tile_id, e.offset, e.length, e.run_length))
+            .collect();
+        assert_eq!(deserialize_directory(&root), expected);
+    }
+
+    #[test]
+    fn directory_leaves() {
+        let entries: Vec<_> = (0..20000u64)
+            .map(|i| Entry {
+                tile_id: i * 3,
+                offset: i * 1000,
+                length: 1000,
+                run_length: 1,
+            })
+            .collect();
+        let (root, leaves) = build_directories(&entries);
+        assert!(root.len() <= ROOT_SIZE);
+        let mut found = vec![];
+        for (tile_id, offset, length, run_length) in deserialize_directory(&root) {
+            // leaf pointers have a run length of 0 and an offset into the leaf section
+            assert_eq!(run_length, 0);
+            let leaf = &leaves[offset as usize..][..length as usize];
+            let leaf = deserialize_directory(leaf);
+            assert_eq!(leaf[0].0, tile_id);
+            found.extend(leaf);
+        }
+        assert_eq!(found.len(), entries.len());
+        assert!(found
+            .iter()
+            .zip(&entries)
+            .all(|(f, e)| f.0 == e.tile_id && f.1 == e.offset));
+    }
+
+    #[test]
+    fn pmtiles_header() {
+        let output = std::env::temp_dir().join(format!("fbrs-pmtiles-{}", std::process::id()));
+        let sink = PmtilesSink {
+            output: output.clone(),
+            extension: "png",
+            surfaces: Default::default(),
+        };
+        sink.write_part("nauvis", 20, -1, 0, vec![1; 3]);
+        sink.write_part("nauvis", 20, 0, 0, vec![2; 5]);
+        sink.write_part("nauvis", 19, -1, 0, vec![3; 7]);
+        let TileLayout::Pmtiles { zoom_offsets } = sink.finish() else {
+            panic!("not a PMTiles layout");
+        };
+        let archive = fs::read(output.join("tiles/nauvis.pmtiles")).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+
+        let u64_at = |i: usize| u64::from_le_bytes(archive[i..i + 8].try_into().unwrap());
+        assert_eq!(&archive[..7], b"PMTiles");
+        assert_eq!(archive[7], 3);
+        let (root_offset, root_len) = (u64_at(8), u64_at(16));
+        let (metadata_offset, metadata_len) = (u64_at(24), u64_at(32));
+        let (leaves_offset, leaves_len) = (u64_at(40), u64_at(48));
+        let (data_offset, data_len) = (u64_at(56), u64_at(64));
+        assert_eq!(root_offset, HEADER_SIZE as u64);
+        assert_eq!(metadata_offset, root_offset + root_len);
+        assert_eq!(leaves_offset, metadata_offset + metadata_len);
+        assert_eq!(leaves_len, 0);
+        assert_eq!(data_offset, leaves_offset);
+        assert_eq!(data_len, 15);
+        assert_eq!(data_offset + data_len, archive.len() as u64);
+        // addressed tiles, entries and contents
+        assert_eq!([u64_at(72), u64_at(80), u64_at(88)], [3, 3, 3]);
+        // tile type png, min and max zoom
+        assert_eq!(archive[99..102], [2, 1, 2]);
+        assert_eq!(zoom_offsets["nauvis"], 18);
+
+        let root = &archive[root_offset as usize..][..root_len as usize];
+        let data = &archive[data_offset as usize..];
+        let mut tiles: Vec<_> = deserialize_directory(root)
+            .into_iter()
+            .map(|(tile_id, offset, length, _)| {
+                (tile_id, data[offset as usize..][..length as usize].to_vec())
+            })
+            .collect();
+        tiles.sort();
+        // z 1 x 0 y 1, z 2 x 1 y 2 and z 2 x 2 y 2 after shifting by half the grid
+        assert_eq!(
+            tiles,
+            [
+                (zxy_to_tile_id(1, 0, 1), vec![3; 7]),
+                (zxy_to_tile_id(2, 1, 2), vec![1; 3]),
+                (zxy_to_tile_id(2, 2, 2), vec![2; 5]),
+            ]
+        );
+    }
+}
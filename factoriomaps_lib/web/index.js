@@ -1,10 +1,79 @@
 "use strict";
 
-const chunkSize = 1 / 1024;
+const chunkSize = mapInfo.tile_size / 2 ** mapInfo.max_zoom;
+const partSize = chunkSize * mapInfo.part_size / mapInfo.tile_size;
 const tileSize = chunkSize / 32;
 
+document.getElementById('map').style.background = `rgb(${mapInfo.background.join(', ')})`;
+
+// same transform as PathScheme::path in sink.rs
+function partPath(name, grid, z, x, y) {
+  const scheme = mapInfo.layout.scheme;
+  if (grid && scheme.non_negative) {
+    const scale = 2 ** (grid.max_zoom - z);
+    x -= Math.floor(grid.min_x / scale) * grid.num_parts;
+    y = scheme.flip_y
+      ? (Math.floor(grid.max_y / scale) + 1) * grid.num_parts - 1 - y
+      : y - Math.floor(grid.min_y / scale) * grid.num_parts;
+  } else if (scheme.flip_y) {
+    y = -1 - y;
+  }
+  if (grid && scheme.rebase_zoom) {
+    z -= grid.min_zoom;
+  }
+  return 'tiles/' + scheme.template
+    .replaceAll('{surface}', name)
+    .replaceAll('{z}', z)
+    .replaceAll('{x}', x)
+    .replaceAll('{y}', y)
+    .replaceAll('{ext}', mapInfo.extension);
+}
+
+function tileSource(name, mapInfoMap) {
+  if (mapInfo.layout.type != 'pmtiles') {
+    return {
+      getTileUrl: function(c) {
+        return mapInfoMap.get(`${c.z},${c.x},${c.y}`) || '';
+      },
+    };
+  }
+
+  // PMTiles coordinates have to be non-negative so they are shifted, see TileLayout
+  const archive = new pmtiles.PMTiles(`tiles/${name}.pmtiles`);
+  const zoomOffset = mapInfo.layout.zoom_offsets[name];
+  return {
+    createTile: function(c, done) {
+      const tile = document.createElement('img');
+      if (!mapInfoMap.has(`${c.z},${c.x},${c.y}`)) {
+        setTimeout(() => done(null, tile));
+        return tile;
+      }
+      const z = c.z - zoomOffset;
+      const half = 2 ** (z - 1);
+      archive.getZxy(z, c.x + half, c.y + half).then(t => {
+        if (!t) return done(null, tile);
+        tile.onload = () => {
+          URL.revokeObjectURL(tile.src);
+          done(null, tile);
+        };
+        tile.onerror = () => {
+          URL.revokeObjectURL(tile.src);
+          done(new Error(`can't decode tile ${z}/${c.x + half}/${c.y + half} of ${name}`), tile);
+        };
+        tile.src = URL.createObjectURL(new Blob([t.data]));
+      }, err => done(err, tile));
+      return tile;
+    },
+  };
+}
+
+let shownSurface = null;
+
 function createLayer(name, surface) {
-  const mapInfoMap = new Map(surface.tiles.map(t => [`${t[0]},${t[1]},${t[2]}`, `tiles/${name}/${t[0]}/${t[1]}/${t[2]}.${mapInfo.extension}`]));
+  const mapInfoMap = new Map(surface.tiles.map(t => [
+    `${t[0]},${t[1]},${t[2]}`,
+    mapInfo.layout.type == 'directory' ? partPath(name, surface.grid, ...t) : '',
+  ]));
 
   let minZoom = +Infinity;
   let maxZoom = -Infinity;
@@ -16,10 +85,11 @@ function createLayer(name, surface) {
   const bounds = L.latLngBounds(surface.tiles
     .filter(([z]) => z == maxZoom)
     .flatMap(([_, x, y]) => [[x, y], [x + 1, y + 1]])
-    .map(([x, y]) => [-y * chunkSize / 2, x * chunkSize / 2]));
+    .map(([x, y]) => [-y * partSize, x * partSize]));
 
   const tileLayer = new (L.TileLayer.extend({
     name,
+    ...tileSource(name, mapInfoMap),
     options: {
       minNativeZoom: minZoom,
       maxNativeZoom: maxZoom,
@@ -27,15 +97,16 @@ function createLayer(name, surface) {
       maxZoom,
       bounds,
       noWrap: true,
-      tileSize: 512,
+      tileSize: mapInfo.part_size,
       keepBuffer: 100,
     },
-    getTileUrl: function(c) {
-      return mapInfoMap.get(`${c.z},${c.x},${c.y}`) || '';
-    },
     onAdd: function(map) {
       L.TileLayer.prototype.onAdd.call(this, map);
-      map.fitBounds(this.options.bounds); // animate = false causes weird things to happen
+      // switching between variants of the same surface keeps the view
+      if (shownSurface != surface.surface) {
+        map.fitBounds(this.options.bounds); // animate = false causes weird things to happen
+      }
+      shownSurface = surface.surface;
     },
   }));
 
@@ -47,39 +118,113 @@ function createLayer(name, surface) {
     });
 
   return {
+    surface: surface.surface,
     group: new L.LayerGroup([tileLayer]),
     tiles: tileLayer,
     markers: L.layerGroup(markers),
+    pruning: surface.pruning_overlay && createPruningLayer(surface.surface, surface.pruning_overlay, bounds),
   };
 }
 
-const layers = Object.entries(mapInfo.surfaces).map(
-  ([name, surface]) => [name, createLayer(name, surface)]
-);
-
-const map = L.map('map', {
-  center: [0, 0],
-  zoom: 16,
-  layers: [layers[0][1].group],
-  fadeAnimation: false,
-  zoomAnimation: true,
-  crs: L.CRS.Simple,
-});
-
-const tagsLayer = new (L.Layer.extend({
-  onAdd: function(_) {
-    for (const [_, layer] of layers) {
-      layer.markers.addTo(layer.group);
-    }
-  },
-  onRemove: function(_) {
-    for (const [_, layer] of layers) {
-      layer.markers.removeFrom(layer.group);
+// chunks coloured by their pruning classification, drawn at a single zoom level
+function createPruningLayer(name, overlay, bounds) {
+  const parts = new Set(overlay.parts.map(([x, y]) => `${x},${y}`));
+  return new (L.TileLayer.extend({
+    getTileUrl: function(c) {
+      return parts.has(`${c.x},${c.y}`) ? `debug-pruning/${name}/${overlay.zoom}/${c.x}/${c.y}.png` : '';
+    },
+    options: {
+      minNativeZoom: overlay.zoom,
+      maxNativeZoom: overlay.zoom,
+      maxZoom: mapInfo.max_zoom,
+      bounds,
+      noWrap: true,
+      tileSize: mapInfo.part_size,
+      className: 'pruning',
+    },
+  }));
+}
+
+// browsers can't read MBTiles, the tiles have to go through a tile server and another viewer
+if (mapInfo.layout.type == 'mbtiles') {
+  const message = document.createElement('p');
+  message.className = 'message';
+  message.textContent = 'This map was rendered to MBTiles, serve tiles/<surface>.mbtiles ' +
+    'with a tile server to view it.';
+  document.getElementById('map').append(message);
+} else {
+  showMap();
+}
+
+function showMap() {
+  // planets first, then other surfaces and space platforms last
+  const kindOrder = kind => ({planet: 0, platform: 2})[kind] ?? 1;
+  const layers = Object.entries(mapInfo.surfaces)
+    .sort(([a, sa], [b, sb]) => kindOrder(sa.kind) - kindOrder(sb.kind) || a.localeCompare(b))
+    .map(([name, surface]) => {
+      const label = surface.kind == 'platform' ? `Platform: ${surface.label}` : surface.label;
+      return [
+        surface.variant ? `${label} (${surface.variant})` : label,
+        createLayer(name, surface),
+        name,
+      ];
+    });
+  // the layer control is keyed by label, platforms can share a name so clashes get the unique
+  // layer name appended
+  const labelCounts = new Map();
+  for (const [label] of layers) {
+    labelCounts.set(label, (labelCounts.get(label) ?? 0) + 1);
+  }
+  for (const layer of layers) {
+    if (labelCounts.get(layer[0]) > 1) {
+      layer[0] = `${layer[0]} [${layer[2]}]`;
     }
-  },
-}));
+  }
+  // nauvis is where every save starts
+  const initialLayer = layers.find(([_, layer]) => layer.surface == 'nauvis') ?? layers[0];
 
-const layerControl = L.control.layers(
-  Object.fromEntries(layers.map(([name, surface]) => [name, surface.group])),
-  {tags: tagsLayer},
-).addTo(map);
+  const map = L.map('map', {
+    center: [0, 0],
+    zoom: 16,
+    layers: [initialLayer[1].group],
+    fadeAnimation: false,
+    zoomAnimation: true,
+    crs: L.CRS.Simple,
+  });
+
+  const tagsLayer = new (L.Layer.extend({
+    onAdd: function(_) {
+      for (const [_, layer] of layers) {
+        layer.markers.addTo(layer.group);
+      }
+    },
+    onRemove: function(_) {
+      for (const [_, layer] of layers) {
+        layer.markers.removeFrom(layer.group);
+      }
+    },
+  }));
+
+  const pruningLayer = new (L.Layer.extend({
+    onAdd: function(_) {
+      for (const [_, layer] of layers) {
+        if (layer.pruning) layer.pruning.addTo(layer.group);
+      }
+    },
+    onRemove: function(_) {
+      for (const [_, layer] of layers) {
+        if (layer.pruning) layer.pruning.removeFrom(layer.group);
+      }
+    },
+  }));
+
+  const overlays = {tags: tagsLayer};
+  if (layers.some(([_, layer]) => layer.pruning)) {
+    overlays.pruning = pruningLayer;
+  }
+
+  const layerControl = L.control.layers(
+    Object.fromEntries(layers.map(([name, surface]) => [name, surface.group])),
+    overlays,
+  ).addTo(map);
+}
@@ -1,3 +1,24 @@
+-- JSON of the ModSettings written to settings.lua by the CLI for every run
+local SETTINGS = require('settings')
+-- 2.0 moved write_file and the JSON functions from game to helpers and added planets and space
+-- platforms, the CLI extracts the mod with the info.json of the running version
+local FACTORIO_2 = tonumber(script.active_mods['base']:match('^(%d+)')) >= 2
+
+function write_json(name, tbl)
+  local api = FACTORIO_2 and helpers or game
+  api.write_file(name, api.table_to_json(tbl))
+end
+
+-- how the viewer names and orders the surface, plain surface name for 1.1
+function describe_surface(surface)
+  if FACTORIO_2 and surface.platform then
+    return 'platform', surface.platform.name
+  elseif FACTORIO_2 and surface.planet then
+    return 'planet', (surface.planet.name:gsub('^%l', string.upper))
+  end
+  return nil, surface.name
+end
+
 function center(area)
   return {(area.left_top.x + area.right_bottom.x) / 2, (area.left_top.y + area.right_bottom.y) / 2}
 end
@@ -35,210 +56,162 @@ function values(tbl)
   return new_tbl
 end
 
-function get_neighbors(chunks, chunk)
-  return filter(map({
-    {x =  0, y = -1},
-    {x =  0, y =  1},
-    {x = -1, y =  0},
-    {x =  1, y =  0},
-  }, function(offset) return chunks[chunk_key{x = chunk.x + offset.x, y = chunk.y + offset.y}] end),
-  function(neighbor) return neighbor ~= nil end)
+-- cheap hash of the entities and placed tiles in a chunk to detect changes between renders
+-- entities that move on their own, hashing them would make every chunk they pass change
+local MOVING_TYPES = {
+  'unit', 'character', 'car', 'spider-vehicle', 'locomotive', 'cargo-wagon', 'fluid-wagon',
+  'artillery-wagon', 'combat-robot', 'construction-robot', 'logistic-robot', 'projectile',
+  'smoke-with-trigger', 'fire', 'stream', 'explosion', 'particle-source',
+}
+
+function hash_chunk(surface, chunk)
+  local h = 5381
+  local function feed(s)
+    for i = 1, #s do
+      h = (h * 33 + s:byte(i)) % 4294967296
+    end
+  end
+  for _, entity in pairs(surface.find_entities_filtered{area = chunk.area, type = MOVING_TYPES, invert = true}) do
+    feed(entity.name .. entity.position.x .. ',' .. entity.position.y .. ',' .. entity.direction)
+  end
+  for _, tile in pairs(surface.find_tiles_filtered{area = chunk.area, has_hidden_tile = true}) do
+    feed(tile.name .. tile.position.x .. ',' .. tile.position.y)
+  end
+  return string.format('%08x', h)
 end
 
-function take_screenshots(player)
-  local info = {}
-
-  for name, surface in pairs(game.surfaces) do
+-- facts about every generated chunk, the CLI decides which to render in prune.rs and starts
+-- Factorio again with them in SELECTED_CHUNKS
+function survey(settings)
+  local surfaces = {}
+  for _, surface in pairs(game.surfaces) do
     local chunks = {}
-
-    -- initialize chunks and whether they contain player entities
     for chunk in surface.get_chunks() do
-      -- player.print("x: " .. chunk.x .. ", y: " .. chunk.y)
-      -- player.print("area: " .. serpent.line(chunk.area))
-
-      local contains_entities = 0 < #surface.find_entities_filtered{area=chunk.area, force=player.force}
-      local contains_tags = false
+      local entities = {}
+      local tags = 0
       for _, force in pairs(game.forces) do
-        if 0 < #force.find_chart_tags(surface, chunk.area) then
-          contains_tags = true
-          break
+        local count = surface.count_entities_filtered{area = chunk.area, force = force}
+        if 0 < count then
+          table.insert(entities, {force = force.name, count = count})
         end
+        tags = tags + #force.find_chart_tags(surface, chunk.area)
       end
-      chunks[chunk_key(chunk)] = {
+      table.insert(chunks, {
         x = chunk.x,
         y = chunk.y,
-        distance = (contains_entities or contains_tags) and 0 or nil,
-        contains_entities = contains_entities,
-        contains_tags = contains_tags,
-      }
+        entities = entities,
+        tags = tags,
+        resources = surface.count_entities_filtered{area = chunk.area, type = 'resource'},
+        hash = settings.incremental and hash_chunk(surface, chunk) or nil,
+      })
     end
-
-    -- calculate residual distances
-    for i=1,5 do
-      for _, chunk in pairs(chunks) do
-        local min = nil
-        for _, neigh in pairs(get_neighbors(chunks, chunk)) do
-          -- print(serpent.line(min) .. ' ' .. serpent.line(neigh.distance))
-          if min == nil or (neigh.distance ~= nil and min > neigh.distance) then
-            min = neigh.distance
-          end
-        end
-        if min ~= nil and (chunk.distance == nil or chunk.distance > min) then
-          chunk.distance = min + 1
-        end
+    -- positions and texts for --crop-tags
+    local tags = {}
+    for _, force in pairs(game.forces) do
+      for _, tag in pairs(force.find_chart_tags(surface)) do
+        table.insert(tags, {x = tag.position.x, y = tag.position.y, text = tag.text})
       end
     end
+    table.insert(surfaces, {name = surface.name, chunks = chunks, tags = tags})
+  end
+  write_json('chunks.json', surfaces)
+end
 
-    -- set flag if within distance of player entity
-    for _, chunk in pairs(chunks) do
-      if chunk.distance ~= nil and chunk.distance < 5 then
-        chunk.within_distance = true
-      else
-        chunk.within_distance = false
-      end
+function take_screenshots()
+  local settings = (FACTORIO_2 and helpers or game).json_to_table(SETTINGS)
+  local selected_chunks = settings.selected_chunks
+  if selected_chunks == nil then
+    survey(settings)
+    return
+  end
+  local previous_chunks = settings.previous_chunks
+  local resumed_chunks = {}
+  for name, keys in pairs(settings.resumed_chunks) do
+    resumed_chunks[name] = {}
+    for _, key in pairs(keys) do
+      resumed_chunks[name][key] = true
     end
+  end
+  local info = {}
 
-    -- find and fill islands
-    local queue = {}
-    for key, chunk in pairs(chunks) do
-      queue[key] = chunk
-    end
-    local key
-    local edge_id = 0
-    while true do
-      local key, chunk = next(queue)
-      if key == nil then
-        break
-      end
-      if chunk.within_distance then
-        chunk.edge = false
-      else
-        -- search area originating from chunk
-        local visited = {}
-        local to_visit = { [key] = chunk }
-        local edge = false
-
-        edge_id = edge_id + 1
-
-        -- iterator neighbors until exhausted
-        while true do
-          local key, chunk = next(to_visit)
-
-          -- all neighbors found
-          if key == nil then
-            for key, chunk in pairs(visited) do
-              chunk.edge = edge
-              chunk.edge_id = edge_id
-              queue[key] = nil
-            end
-            break
-          end
-
-          local neighbors = get_neighbors(chunks, chunk)
-          -- if less than 4 neighbors then edge of map found
-          if #neighbors < 4 then
-            edge = true
-          end
-
-          for _, chunk in pairs(neighbors) do
-            local key = chunk_key(chunk)
-            if not chunk.within_distance and not visited[key] then
-              to_visit[key] = chunk
-            end
-          end
-
-          -- remove current chunk from queue and add to visited
-          visited[key] = chunk
-          to_visit[key] = nil
-        end
+  for name, chunks in pairs(selected_chunks) do
+    local surface = game.surfaces[name]
 
+    -- omit surface entirely if there are no visible chunks
+    if surface ~= nil and #chunks > 0 then
+      -- build tags object
+      local tags = {}
+      for _, force in pairs(game.forces) do
+        local f = map(force.find_chart_tags(surface), function(tag) return {
+          position = tag.position,
+          text = tag.text,
+        } end)
+        if 0 < #f then
+          tags[force.name] = f
+        end
       end
-      queue[key] = nil
-    end
 
-    -- build tags object
-    local tags = {}
-    for _, force in pairs(game.forces) do
-       local f = map(force.find_chart_tags(surface), function(tag) return {
-        position = tag.position,
-        text = tag.text,
-      } end)
-      if 0 < #f then
-        tags[force.name] = f
-      end
+      local kind, label = describe_surface(surface)
+      table.insert(info, {
+        name = name,
+        kind = kind,
+        label = label,
+        tags = tags,
+        chunks = chunks,
+      })
     end
+  end
+  write_json('info.json', info)
 
-    -- write information about the current surface to a file
-    local surface_info = {
-      name = surface.name,
-      tags = tags,
-      chunks = map(filter(values(chunks), function(chunk) return not chunk.edge end), function(chunk) return {x = chunk.x, y = chunk.y} end),
-    }
-
-    -- omit surface entirely if there are no visible chunks
-    if #surface_info.chunks > 0 then
-      table.insert(info, surface_info)
-    end
+  -- always_day lights the screenshots like noon whatever their daytime, so it is only forced
+  -- if none has a daytime
+  local any_daytime = false
+  for _, screenshot in pairs(settings.screenshots) do
+    any_daytime = any_daytime or screenshot.daytime ~= nil
   end
-  game.write_file('info.json', game.table_to_json(info))
 
   for i, surface_info in pairs(info) do
     local surface = game.surfaces[surface_info.name]
 
-    surface.always_day = true
-
-    -- create map tags
-    if false then
-      for _, tag in pairs(player.force.find_chart_tags(surface)) do
-        tag.destroy()
-      end
+    surface.always_day = not any_daytime
+    if settings.hide_decoratives then
+      surface.destroy_decoratives{}
+    end
 
-      for _, chunk in pairs(chunks) do
-        local icon = 'signal-black'
-        if chunk.edge == true then
-          icon = 'signal-green'
-        elseif chunk.edge == false then
-          icon = 'signal-red'
-        end
-        -- local icon = 'signal-red'
-        -- if chunk.contains_entities then
-        --   icon = 'signal-green'
-        -- elseif chunk.within_distance then
-        --   icon = 'signal-black'
-        -- end
-        player.force.add_chart_tag(surface, {
-          position = {chunk.x * 32 + 16, chunk.y * 32 + 16},
-          -- text = key(chunk) .. ',' .. serpent.line(chunk.distance),
-          text = tostring(chunk.edge_id or ''),
-          icon = {type='virtual', name=icon}
-        })
+    -- every variant is its own layer named surface/variant, see split_variants
+    for _, screenshot in pairs(settings.screenshots) do
+      local layer = surface.name
+      local suffix = ''
+      if screenshot.variant then
+        layer = surface.name .. '/' .. screenshot.variant
+        suffix = ',' .. screenshot.variant
       end
-      -- print(serpent.block(chunks))
-    else
+      local previous = previous_chunks[layer] or {}
+      local resumed = resumed_chunks[layer] or {}
       for _, chunk in pairs(surface_info.chunks) do
-        if not chunk.edge then
+        -- must match the unchanged and finished checks in ThreadContext::new
+        local unchanged = chunk.hash ~= nil and previous[chunk_key(chunk)] == chunk.hash
+        if not unchanged and not resumed[chunk_key(chunk)] then
           game.take_screenshot({
             surface = surface,
             position = {chunk.x * 32 + 16, chunk.y * 32 + 16},
-            resolution = {1024, 1024},
-            zoom = 1,
-            path = surface.name .. ',' .. chunk.x .. ',' .. chunk.y .. '.bmp',
-            show_entity_info = true
+            resolution = {screenshot.resolution, screenshot.resolution},
+            zoom = screenshot.zoom,
+            path = surface.name .. ',' .. chunk.x .. ',' .. chunk.y .. suffix .. '.bmp',
+            show_entity_info = screenshot.show_entity_info,
+            anti_alias = screenshot.anti_alias,
+            daytime = screenshot.daytime
           })
         end
       end
     end
-
-    -- game.print(serpent.line(get_neighbors(chunks, {x = 0, y = 0})))
   end
 end
 
 script.on_event(defines.events.on_tick, function(event)
   game.set_wait_for_screenshots_to_finish()
 
-  local player = game.connected_players[1]
-
-  take_screenshots(player)
+  take_screenshots()
 
   game.print('screenshot finished')
 
@@ -1,8 +1,18 @@
-use std::fs::{self, File};
+use std::collections::HashMap;
+use std::fs;
 use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
-use fs2::FileExt;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use factoriomaps_lib::compat;
+use factoriomaps_lib::crop::{CropArea, CropConfig};
+use factoriomaps_lib::export::{self, ExportKind};
+use factoriomaps_lib::prune::{self, PruneConfig, SelectedChunk, SurfaceFacts};
+use factoriomaps_lib::render::{
+    BoundingBox, Journal, Manifest, RenderConfig, ResizeFilter, Rgb, ScreenshotConfig, TileFormat,
+    Variant, ZoomRange,
+};
+use factoriomaps_lib::sink::{PathScheme, SinkKind};
 use include_dir::{include_dir, Dir};
 use serde::{Deserialize, Serialize};
 
@@ -18,6 +28,73 @@ struct FactorioModEntry {
     enabled: bool,
 }
 
+/// Everything the mod reads in a run, written to `settings.lua` next to `control.lua`
+#[derive(Debug, Serialize)]
+struct ModSettings<'a> {
+    /// Report chunk hashes to skip unchanged chunks
+    incremental: bool,
+    hide_decoratives: bool,
+    /// Taken of every chunk, one per layer of each surface
+    screenshots: Vec<ScreenshotSettings>,
+    /// Chunk hashes of the previous render keyed by layer and then "x,y", empty unless
+    /// incremental
+    previous_chunks: HashMap<String, HashMap<String, String>>,
+    /// "x,y" keys of chunks already written by an interrupted render keyed by layer
+    resumed_chunks: HashMap<String, Vec<String>>,
+    /// Chunks to render keyed by surface, `None` for the survey run that reports chunks.json
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selected_chunks: Option<&'a HashMap<String, Vec<SelectedChunk>>>,
+}
+/// Parameters of `game.take_screenshot`
+#[derive(Debug, Serialize)]
+struct ScreenshotSettings {
+    /// Appended to the file name and layer, see `render::split_variants`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
+    /// Pixels along each side, a whole chunk
+    resolution: u32,
+    /// Zoom 1 renders a chunk at 1024px
+    zoom: f64,
+    anti_alias: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daytime: Option<f64>,
+    show_entity_info: bool,
+}
+impl ModSettings<'_> {
+    fn screenshots(config: &RenderConfig) -> Vec<ScreenshotSettings> {
+        let screenshot = |variant, daytime, show_entity_info| ScreenshotSettings {
+            variant,
+            resolution: config.tile_size,
+            zoom: config.tile_size as f64 / 1024.0,
+            anti_alias: config.screenshot.anti_alias,
+            daytime,
+            show_entity_info,
+        };
+        if config.variants.is_empty() {
+            return vec![screenshot(
+                None,
+                config.screenshot.daytime,
+                config.screenshot.show_entity_info,
+            )];
+        }
+        config
+            .variants
+            .iter()
+            .map(|v| screenshot(Some(v.name.clone()), Some(v.daytime), v.show_entity_info))
+            .collect()
+    }
+    /// Lua source returning the settings as a JSON string
+    fn to_lua(&self) -> String {
+        let json = serde_json::to_string(self).unwrap();
+        // long bracket level the JSON can't end early
+        let mut level = String::new();
+        while json.contains(&format!("]{level}]")) {
+            level.push('=');
+        }
+        format!("return [{level}[{json}]{level}]\n")
+    }
+}
+
 #[derive(Parser)]
 struct Args {
     #[command(subcommand)]
@@ -26,7 +103,12 @@ struct Args {
 
 #[derive(Subcommand)]
 enum Action {
-    Render(ActionRender),
+    Render(Box<ActionRender>),
+    /// Stitch one zoom level of a finished render into a single PNG or TIFF image
+    Poster(ActionPoster),
+    /// Build a map from info.json and <surface>,<x>,<y>.bmp or .png screenshots in a directory
+    /// without running Factorio
+    Replay(ActionReplay),
 }
 
 #[derive(Parser)]
@@ -35,19 +117,235 @@ struct ActionRender {
     factorio: PathBuf,
     /// Render output path
     output: PathBuf,
-    /// Path to map to render
+    /// Path to map to render or name of a save of the install
     map: String,
     /// By default Xvfb will be used to run factorio in the background. Set this flag to make
     /// the window visible
     #[clap(long, short)]
     debug: bool,
+    /// Size in pixels of the screenshot taken for each chunk
+    #[clap(long, default_value_t = 1024)]
+    tile_size: u32,
+    /// Number of parts each tile is split into along each axis for the viewer, has to divide
+    /// --tile-size
+    #[clap(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    parts: u32,
+    /// Zoom level of the chunk screenshots
+    #[clap(long, default_value_t = 20)]
+    max_zoom: i32,
+    /// Lowest zoom level built for every surface, at most 30 below --max-zoom. By default
+    /// surfaces are zoomed out until they fit in about 32 tiles
+    #[clap(
+        long,
+        conflicts_with_all = ["zoom_levels", "overview_size"],
+        allow_hyphen_values = true
+    )]
+    min_zoom: Option<i32>,
+    /// Number of zoom levels built for every surface, including --max-zoom, at most 31
+    #[clap(
+        long,
+        conflicts_with = "overview_size",
+        value_parser = clap::value_parser!(u32).range(1..=ZoomRange::MAX_LEVELS as i64)
+    )]
+    zoom_levels: Option<u32>,
+    /// Zoom every surface out until it fits in this many pixels along its longest axis
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    overview_size: Option<u32>,
+    /// Chunks rendered around every chunk with entities of a --prune-force or map tags
+    #[clap(long, default_value_t = 4)]
+    prune_margin: u32,
+    /// Don't render areas outside the margin that are enclosed by it
+    #[clap(long)]
+    no_fill_islands: bool,
+    /// Forces whose entities are rendered with their surroundings, * for every force other than
+    /// enemy and neutral. Can be given multiple times
+    #[clap(long, default_value = "player")]
+    prune_force: Vec<String>,
+    /// Also render the surroundings of resources
+    #[clap(long)]
+    prune_resources: bool,
+    /// Add a viewer overlay colouring every chunk by how the pruning classified it
+    #[clap(long)]
+    debug_pruning: bool,
+    /// Only render surfaces matching this name, * matches any characters. Can be given
+    /// multiple times, renders every surface by default
+    #[clap(long)]
+    surface: Vec<String>,
+    /// Don't render surfaces matching this name, * matches any characters. Can be given
+    /// multiple times
+    #[clap(long)]
+    exclude_surface: Vec<String>,
+    /// Only render chunks overlapping left,top,right,bottom in game coordinates
+    #[clap(long, conflicts_with_all = ["crop_polygon", "crop_tags"], allow_hyphen_values = true)]
+    crop_bbox: Option<BoundingBox>,
+    /// Only render chunks overlapping the polygon x1,y1,x2,y2,... in game coordinates
+    #[clap(
+        long,
+        conflicts_with = "crop_tags",
+        value_delimiter = ',',
+        allow_hyphen_values = true
+    )]
+    crop_polygon: Vec<f64>,
+    /// Only render chunks within --crop-radius of map tags whose text matches this, * matches
+    /// any characters
+    #[clap(long)]
+    crop_tags: Option<String>,
+    /// Distance in game tiles around the map tags matched by --crop-tags that is rendered
+    #[clap(long, default_value_t = 256.0)]
+    crop_radius: f64,
+    /// Render every surface once per variant as its own layer: day, night, no-alt-mode or
+    /// name:option,... with the options daytime=<0-1> and alt-mode=on|off. Can be given
+    /// multiple times
+    #[clap(long)]
+    variant: Vec<Variant>,
+    /// Smooth edges in the screenshots, slower to take
+    #[clap(long)]
+    anti_alias: bool,
+    /// Time of day of the screenshots, 0 is noon and 0.5 midnight. Always day by default.
+    /// Replaced by the daytime of each --variant
+    #[clap(long, value_parser = parse_daytime)]
+    daytime: Option<f64>,
+    /// Hide alt-mode icons and overlays. Replaced by the alt-mode of each --variant
+    #[clap(long)]
+    no_entity_info: bool,
+    /// Remove decoratives such as grass and flowers before taking screenshots
+    #[clap(long)]
+    hide_decoratives: bool,
+    /// Tile encoding: jpeg, webp, webp-lossless, png or qoi (not viewable in browsers)
+    #[clap(long, default_value = "jpeg")]
+    format: TileFormat,
+    /// Quality of lossy tile encodings, 0-100
+    #[clap(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(0..=100))]
+    quality: u8,
+    /// Keep transparent areas transparent instead of flattening onto --matte. Requires a
+    /// format with alpha support
+    #[clap(long)]
+    transparent: bool,
+    /// Colour transparent areas are flattened onto and the viewer background
+    #[clap(long, default_value = "#1b2d33")]
+    matte: Rgb,
+    /// Filter used to downscale lower zoom levels: box, bilinear, catmull-rom or lanczos3
+    #[clap(long, default_value = "lanczos3")]
+    filter: ResizeFilter,
+    /// Downscale lower zoom levels in linear light so colours match the game instead of
+    /// darkening
+    #[clap(long)]
+    linear: bool,
+    /// Memory in MiB finished tiles may use while waiting for their siblings before being
+    /// spilled to a cache on disk. Unlimited by default
+    #[clap(long)]
+    memory_budget: Option<usize>,
+    /// Jobs queued per pipeline stage before Factorio's screenshot writer is paused
+    #[clap(
+        long,
+        default_value_t = 16,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    queue_depth: usize,
+    /// Threads encoding and writing tiles, defaults to the number of CPUs
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    encode_threads: Option<usize>,
+    /// Threads downscaling parent tiles, defaults to the number of CPUs
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    resize_threads: Option<usize>,
+    /// Only render chunks that changed since the previous render into the same output. Not
+    /// supported with --rebase-zoom or --non-negative, the extent of the map may change
+    /// between renders
+    #[clap(long, conflicts_with_all = ["rebase_zoom", "non_negative"])]
+    incremental: bool,
+    /// Continue a render into the same output that was interrupted, skipping finished chunks.
+    /// Options affecting the output must match the interrupted render
+    #[clap(long)]
+    resume: bool,
+    /// How tiles are stored: directory (one file per tile), pmtiles or mbtiles (one file per
+    /// surface)
+    #[clap(long, default_value = "directory")]
+    sink: SinkKind,
+    /// Path of each tile below tiles/ for --sink directory, with {surface}, {z}, {x}, {y} and
+    /// {ext} placeholders
+    #[clap(long, default_value = "{surface}/{z}/{x}/{y}.{ext}")]
+    path_template: String,
+    /// Number zoom levels from 0 at the lowest zoom level of each surface
+    #[clap(long)]
+    rebase_zoom: bool,
+    /// Number rows from the bottom like TMS instead of from the top like XYZ
+    #[clap(long)]
+    flip_y: bool,
+    /// Shift tile coordinates of each zoom level so they start at 0
+    #[clap(long)]
+    non_negative: bool,
+    /// Also lay the tiles out for other viewers: dzi (Deep Zoom) or iiif (IIIF Image API
+    /// level 0). Can be given multiple times
+    #[clap(long)]
+    export: Vec<ExportKind>,
+    /// Absolute URL the iiif export directory is served from, used in the IIIF image ids.
+    /// Required by --export iiif
+    #[clap(long)]
+    iiif_base_url: Option<String>,
+}
+
+#[derive(Parser)]
+struct ActionPoster {
+    /// Output path of a finished render
+    output: PathBuf,
+    /// Image to write, .png, .tif or .tiff
+    file: PathBuf,
+    /// Surface to stitch
+    #[clap(long, default_value = "nauvis")]
+    surface: String,
+    /// Zoom level to stitch, defaults to the max zoom of the render
+    #[clap(long)]
+    zoom: Option<i32>,
+    /// Area to stitch in game coordinates as left,top,right,bottom, defaults to the whole
+    /// surface
+    #[clap(long, allow_hyphen_values = true)]
+    bbox: Option<BoundingBox>,
+}
+
+#[derive(Parser)]
+struct ActionReplay {
+    /// Directory with info.json and the screenshots
+    input: PathBuf,
+    /// Render output path
+    output: PathBuf,
+    /// JSON file with the render config, options left out keep their defaults
+    #[clap(long)]
+    config: Option<PathBuf>,
+}
+
+fn parse_daytime(s: &str) -> Result<f64, String> {
+    let daytime: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=1.0).contains(&daytime) {
+        return Err("has to be between 0 and 1".to_owned());
+    }
+    Ok(daytime)
+}
+
+/// Exits like clap does for combinations of arguments it can't check itself
+fn usage_error(kind: ErrorKind, message: impl std::fmt::Display) -> ! {
+    Args::command().error(kind, message).exit()
 }
 
 fn main() {
     let args = Args::parse().action;
     match args {
         Action::Render(action) => {
-            render(action);
+            render(*action);
+        }
+        Action::Poster(action) => {
+            factoriomaps_lib::poster::poster(
+                &action.output,
+                &action.surface,
+                action.zoom,
+                action.bbox,
+                &action.file,
+            );
+        }
+        Action::Replay(action) => {
+            let config: RenderConfig = action.config.map_or_else(Default::default, |path| {
+                serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+            });
+            factoriomaps_lib::replay::replay(&action.input, &action.output, &config);
         }
     }
 }
@@ -74,36 +372,83 @@ impl std::ops::DerefMut for ChildGuard {
     }
 }
 
+/// Temporary write-data directory Factorio runs against, so the install is never written to
+///
+/// Holds a copy of the user's `config.ini` pointed at it, a copy of the rendered save, the mods of the install linked
+/// in, a copy of the mod list with our mod enabled and our mod itself. Removed on drop, anything
+/// a crash leaves behind is in the system temp directory.
 struct SetupGuard {
+    /// Picks the variant of the mod, see `mod/info.json`
+    factorio_version: compat::Version,
+    data_path: PathBuf,
+    config_path: PathBuf,
+    /// Copy of the save Factorio loads
+    map_path: PathBuf,
     mod_path: PathBuf,
     lib_path: PathBuf,
-    modlist_path: PathBuf,
-    modlist_str: String,
 }
 impl SetupGuard {
-    fn new<P: AsRef<Path>>(factorio: P, output: P, map: &str) -> Self {
-        // check factorio lockfile
-        if let Ok(lockfile) = File::open(factorio.as_ref().join(".lock")) {
-            lockfile
-                .try_lock_exclusive()
-                .expect("Could not open lockfile, is factorio already running?");
-            lockfile.unlock().unwrap();
+    fn new<P: AsRef<Path>>(
+        factorio: P,
+        output: P,
+        map: &str,
+        factorio_version: compat::Version,
+    ) -> Self {
+        let factorio = fs::canonicalize(factorio).unwrap();
+        let user_data = user_data_path(&factorio);
+        let data_path =
+            std::env::temp_dir().join(format!("factoriomaps-rs-{}", std::process::id()));
+        fs::remove_dir_all(&data_path).ok();
+        fs::create_dir_all(data_path.join("mods")).unwrap();
+
+        // graphics settings and the like affect the screenshots, only the paths are replaced
+        let config_path = data_path.join("config.ini");
+        let user_config =
+            fs::read_to_string(user_data.join("config/config.ini")).unwrap_or_default();
+        fs::write(
+            &config_path,
+            config_ini(&user_config, &factorio.join("data"), &data_path),
+        )
+        .unwrap();
+
+        // only the rendered save is copied in, so autosaves and anything else Factorio saves
+        // during the run stay out of the install
+        let save = find_save(&user_data, map);
+        fs::create_dir_all(data_path.join("saves")).unwrap();
+        let map_path = data_path.join("saves").join(save.file_name().unwrap());
+        fs::copy(&save, &map_path).unwrap();
+
+        // mods are linked in, files Factorio writes are copied
+        let modname = "factoriomaps-rs";
+        if let Ok(entries) = fs::read_dir(user_data.join("mods")) {
+            for entry in entries {
+                let entry = entry.unwrap();
+                let name = entry.file_name();
+                let target = data_path.join("mods").join(&name);
+                if name == "mod-list.json" || name == "mod-settings.dat" {
+                    fs::copy(entry.path(), target).unwrap();
+                } else if name != modname {
+                    std::os::unix::fs::symlink(entry.path(), target).unwrap();
+                }
+            }
         }
 
         let mut sync_mods = ChildGuard(
-            std::process::Command::new(factorio.as_ref().join("bin/x64/factorio"))
+            std::process::Command::new(factorio.join("bin/x64/factorio"))
+                .arg("--config")
+                .arg(&config_path)
                 .arg("--sync-mods")
-                .arg(map)
+                .arg(&map_path)
                 .spawn()
                 .unwrap(),
         );
         sync_mods.wait().unwrap();
 
-        // insert self into factorio mod list and save original to restore later
-        let modname = "factoriomaps-rs";
-        let modlist_path = factorio.as_ref().join("mods/mod-list.json");
-        let modlist_str = fs::read_to_string(&modlist_path).unwrap();
-        let mut modlist: FactorioMods = serde_json::from_str(&modlist_str).unwrap();
+        // insert self into the copied mod list
+        let modlist_path = data_path.join("mods/mod-list.json");
+        let mut modlist: FactorioMods = fs::read_to_string(&modlist_path)
+            .map(|modlist| serde_json::from_str(&modlist).unwrap())
+            .unwrap_or(FactorioMods { mods: vec![] });
         let mut found = false;
         for entry in &mut modlist.mods {
             if entry.name == modname {
@@ -119,10 +464,8 @@ impl SetupGuard {
             });
         }
         fs::write(&modlist_path, serde_json::to_vec_pretty(&modlist).unwrap()).unwrap();
-        let mod_path = factorio.as_ref().join("mods").join(modname);
-        fs::remove_dir_all(&mod_path).ok();
+        let mod_path = data_path.join("mods").join(modname);
         fs::create_dir(&mod_path).unwrap();
-        MOD.extract(&mod_path).unwrap();
         let lib_path = mod_path.join("libfactoriomaps_lib.so");
         fs::write(
             &lib_path,
@@ -133,17 +476,129 @@ impl SetupGuard {
         std::fs::create_dir_all(output).unwrap();
 
         Self {
-            modlist_path,
-            modlist_str,
+            factorio_version,
+            data_path,
+            config_path,
+            map_path,
             mod_path,
             lib_path,
         }
     }
+
+    /// Writes the mod, which only reports `chunks.json` for pruning without `selected` chunks
+    fn extract_mod<P: AsRef<Path>>(
+        &self,
+        output: P,
+        config: &RenderConfig,
+        selected: Option<&HashMap<String, Vec<SelectedChunk>>>,
+    ) {
+        let settings = ModSettings {
+            incremental: config.incremental,
+            hide_decoratives: config.screenshot.hide_decoratives,
+            screenshots: ModSettings::screenshots(config),
+            // chunk hashes of the previous render so the mod can skip unchanged chunks
+            previous_chunks: config
+                .incremental
+                .then(|| Manifest::read_compatible(&output, config))
+                .flatten()
+                .map(|manifest| {
+                    manifest
+                        .surfaces
+                        .into_iter()
+                        .map(|(name, surface)| {
+                            let chunks = surface
+                                .chunks
+                                .into_iter()
+                                .filter_map(|(key, hash)| Some((key, hash?)))
+                                .collect();
+                            (name, chunks)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            resumed_chunks: if config.resume {
+                Journal::read_chunks(&output, config)
+            } else {
+                Default::default()
+            },
+            selected_chunks: selected,
+        };
+        let compat::Version(major, minor, _) = self.factorio_version;
+        let find_replace =
+            HashMap::from([("$FACTORIO_VERSION$".to_owned(), format!("{major}.{minor}"))]);
+        factoriomaps_lib::render::extract_dir(&MOD, &self.mod_path, &find_replace).unwrap();
+        fs::write(self.mod_path.join("settings.lua"), settings.to_lua()).unwrap();
+    }
 }
 impl Drop for SetupGuard {
     fn drop(&mut self) {
-        fs::write(&self.modlist_path, self.modlist_str.as_bytes()).unwrap();
-        fs::remove_dir_all(&self.mod_path).unwrap();
+        // removes the links, not what they point to
+        if let Err(e) = fs::remove_dir_all(&self.data_path) {
+            println!("Could not remove {}: {e}", self.data_path.display());
+        }
+    }
+}
+
+/// Returns `user_config` with `read-data` and `write-data` in its `[path]` section replaced
+fn config_ini(user_config: &str, read_data: &Path, write_data: &Path) -> String {
+    let paths = format!(
+        "[path]\nread-data={}\nwrite-data={}\n",
+        read_data.display(),
+        write_data.display()
+    );
+    let mut config = String::new();
+    let mut in_path = false;
+    let mut found = false;
+    for line in user_config.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_path = trimmed == "[path]";
+            if in_path {
+                config.push_str(&paths);
+                found = true;
+                continue;
+            }
+        } else if in_path {
+            let key = trimmed
+                .split_once('=')
+                .map_or(trimmed, |(key, _)| key)
+                .trim();
+            if key == "read-data" || key == "write-data" {
+                continue;
+            }
+        }
+        config.push_str(line);
+        config.push('\n');
+    }
+    if !found {
+        config.insert_str(0, &paths);
+    }
+    config
+}
+
+/// Returns the save `map` is a path to or the name of in the saves of `user_data`
+fn find_save(user_data: &Path, map: &str) -> PathBuf {
+    let saves = user_data.join("saves");
+    [
+        PathBuf::from(map),
+        saves.join(map),
+        saves.join(format!("{map}.zip")),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+    .unwrap_or_else(|| panic!("no save {map} or {map}.zip in {}", saves.display()))
+}
+
+/// Write-data directory of the install at `factorio`, `~/.factorio` for system installs
+fn user_data_path(factorio: &Path) -> PathBuf {
+    let system = fs::read_to_string(factorio.join("config-path.cfg")).is_ok_and(|cfg| {
+        cfg.lines()
+            .any(|line| line.trim() == "use-system-read-write-data-directories=true")
+    });
+    if system {
+        PathBuf::from(std::env::var("HOME").unwrap()).join(".factorio")
+    } else {
+        factorio.to_owned()
     }
 }
 
@@ -154,14 +609,170 @@ fn render(action: ActionRender) {
             output,
             map,
             debug,
+            tile_size,
+            parts,
+            max_zoom,
+            min_zoom,
+            zoom_levels,
+            overview_size,
+            prune_margin,
+            no_fill_islands,
+            prune_force,
+            prune_resources,
+            debug_pruning,
+            surface,
+            exclude_surface,
+            crop_bbox,
+            crop_polygon,
+            crop_tags,
+            crop_radius,
+            variant,
+            anti_alias,
+            daytime,
+            no_entity_info,
+            hide_decoratives,
+            format,
+            quality,
+            transparent,
+            matte,
+            filter,
+            linear,
+            memory_budget,
+            queue_depth,
+            encode_threads,
+            resize_threads,
+            incremental,
+            resume,
+            sink,
+            path_template,
+            rebase_zoom,
+            flip_y,
+            non_negative,
+            export,
+            iiif_base_url,
         } = action;
-        let setup_guard = SetupGuard::new(&factorio, &output, &map);
-
-        let mut factorio_cmd = std::process::Command::new(factorio.join("bin/x64/factorio"));
+        if tile_size % parts != 0 {
+            usage_error(
+                ErrorKind::ValueValidation,
+                "--tile-size must be divisible by --parts",
+            );
+        }
+        if min_zoom.is_some_and(|zoom| max_zoom - zoom >= ZoomRange::MAX_LEVELS as i32) {
+            usage_error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--min-zoom must be at most {} below --max-zoom",
+                    ZoomRange::MAX_LEVELS - 1
+                ),
+            );
+        }
+        if !crop_polygon.is_empty() && (crop_polygon.len() % 2 != 0 || crop_polygon.len() < 6) {
+            usage_error(
+                ErrorKind::ValueValidation,
+                "--crop-polygon needs at least three x,y pairs",
+            );
+        }
+        let config = RenderConfig {
+            tile_size,
+            num_parts: parts,
+            max_zoom,
+            zoom_range: match (min_zoom, zoom_levels, overview_size) {
+                (Some(zoom), _, _) => ZoomRange::MinZoom(zoom),
+                (_, Some(levels), _) => ZoomRange::Levels(levels),
+                (_, _, Some(px)) => ZoomRange::Overview(px),
+                _ => ZoomRange::Auto,
+            },
+            prune: PruneConfig {
+                margin: prune_margin,
+                fill_islands: !no_fill_islands,
+                forces: prune_force,
+                resources: prune_resources,
+            },
+            debug_pruning,
+            crop: CropConfig {
+                include: surface,
+                exclude: exclude_surface,
+                area: match (crop_bbox, crop_tags) {
+                    (Some(bbox), _) => Some(CropArea::BoundingBox(bbox)),
+                    (_, Some(pattern)) => Some(CropArea::Tags {
+                        pattern,
+                        radius: crop_radius,
+                    }),
+                    _ if !crop_polygon.is_empty() => Some(CropArea::Polygon(
+                        crop_polygon.chunks(2).map(|p| [p[0], p[1]]).collect(),
+                    )),
+                    _ => None,
+                },
+            },
+            variants: variant,
+            screenshot: ScreenshotConfig {
+                anti_alias,
+                daytime,
+                show_entity_info: !no_entity_info,
+                hide_decoratives,
+            },
+            format,
+            quality,
+            transparent,
+            matte,
+            filter,
+            linear,
+            memory_budget: memory_budget.map(|mib| mib * 1024 * 1024),
+            queue_depth,
+            encode_threads,
+            resize_threads,
+            incremental,
+            resume,
+            sink,
+            path_scheme: PathScheme {
+                template: path_template,
+                rebase_zoom,
+                flip_y,
+                non_negative,
+            },
+            exports: export,
+            iiif_base_url,
+        };
+        if !sink.readable() && (incremental || resume || !config.exports.is_empty()) {
+            usage_error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--incremental, --resume and --export are not supported by --sink {sink:?}"
+                ),
+            );
+        }
+        if let Err(e) = export::validate(&config) {
+            usage_error(ErrorKind::ValueValidation, format!("--iiif-base-url {e}"));
+        }
+        let mut variant_names: Vec<&str> = config.variants.iter().map(|v| &v.name[..]).collect();
+        variant_names.sort();
+        variant_names.dedup();
+        if variant_names.len() != config.variants.len() {
+            usage_error(ErrorKind::ValueValidation, "--variant names must be unique");
+        }
+        if transparent && !config.encoder().supports_alpha() {
+            usage_error(
+                ErrorKind::ArgumentConflict,
+                format!("--transparent is not supported by --format {format:?}"),
+            );
+        }
+        if sink != SinkKind::Directory && format == TileFormat::Qoi {
+            usage_error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--format qoi is not supported by --sink {sink:?}, archives only store png, \
+                     jpg and webp"
+                ),
+            );
+        }
+        // the injected lib reads screenshots from Factorio's memory, refuse versions it doesn't
+        // know the layout of before starting anything
+        let version = compat::detect(&factorio).unwrap_or_else(|e| panic!("{e}"));
+        compat::abi(version).unwrap_or_else(|e| panic!("{e}"));
+        println!("Factorio {version}");
+        let setup_guard = SetupGuard::new(&factorio, &output, &map, version);
 
         let _xvfb = if !debug {
-            factorio_cmd.env("DISPLAY", ":8");
-
             Some(ChildGuard(
                 std::process::Command::new("Xvfb")
                     .arg(":8") // TODO don't assume :8 isn't being used
@@ -175,39 +786,130 @@ fn render(action: ActionRender) {
             None
         };
 
-        let mut factorio = ChildGuard(
-            factorio_cmd
-                .env("LD_PRELOAD", &setup_guard.lib_path)
-                .env("FBRS_OUTPUT", output)
-                .arg("--disable-audio")
-                .arg("--disable-migration-window")
-                // --benchmark-graphics unpauses the game, but swollows errors
-                // --load-game is to figure out why something broke
-                .arg(if debug {
-                    "--load-game"
-                } else {
-                    "--benchmark-graphics"
-                })
-                .arg(map)
-                //.stdout(std::process::Stdio::null()) // TODO scan output for errors?
-                .spawn()
-                .unwrap(),
-        );
-
-        let (tx, rx) = crossbeam::channel::unbounded::<()>();
-
-        let ctrlc_tx = tx.clone();
+        let (interrupt_tx, interrupted) = crossbeam::channel::unbounded::<()>();
         ctrlc::set_handler(move || {
-            ctrlc_tx.send(()).unwrap();
+            interrupt_tx.send(()).unwrap();
         })
         .unwrap();
 
-        std::thread::spawn(move || {
-            factorio.wait().unwrap();
-            tx.send(()).unwrap();
-        });
+        // the survey run reports every generated chunk, a resumed render keeps the chunks the
+        // interrupted one selected
+        let chunks_path = output.join(prune::FACTS_FILE);
+        if !(resume && chunks_path.exists()) {
+            fs::remove_file(&chunks_path).ok();
+            setup_guard.extract_mod(&output, &config, None);
+            if !run_factorio(
+                &factorio,
+                debug,
+                &setup_guard,
+                &output,
+                &config,
+                &interrupted,
+            ) {
+                return;
+            }
+        }
+        let facts: Vec<SurfaceFacts> = serde_json::from_slice(
+            &fs::read(&chunks_path).expect("Factorio exited without reporting chunks"),
+        )
+        .unwrap();
+        let selected = prune::select(&config.prune, &config.crop, &facts);
 
-        rx.recv().unwrap()
+        setup_guard.extract_mod(&output, &config, Some(&selected));
+        run_factorio(
+            &factorio,
+            debug,
+            &setup_guard,
+            &output,
+            &config,
+            &interrupted,
+        );
     })
     .unwrap();
 }
+
+/// Runs Factorio with the lib injected until it exits, returns false if interrupted
+fn run_factorio(
+    factorio: &Path,
+    debug: bool,
+    setup_guard: &SetupGuard,
+    output: &Path,
+    config: &RenderConfig,
+    interrupted: &crossbeam::channel::Receiver<()>,
+) -> bool {
+    let mut factorio_cmd = std::process::Command::new(factorio.join("bin/x64/factorio"));
+    if !debug {
+        factorio_cmd.env("DISPLAY", ":8");
+    }
+    let mut factorio = ChildGuard(
+        factorio_cmd
+            .env("LD_PRELOAD", &setup_guard.lib_path)
+            .env("FBRS_OUTPUT", output)
+            .env("FBRS_CONFIG", serde_json::to_string(config).unwrap())
+            .env(
+                "FBRS_FACTORIO_VERSION",
+                setup_guard.factorio_version.to_string(),
+            )
+            .arg("--config")
+            .arg(&setup_guard.config_path)
+            .arg("--disable-audio")
+            .arg("--disable-migration-window")
+            // --benchmark-graphics unpauses the game, but swollows errors
+            // --load-game is to figure out why something broke
+            .arg(if debug {
+                "--load-game"
+            } else {
+                "--benchmark-graphics"
+            })
+            .arg(&setup_guard.map_path)
+            //.stdout(std::process::Stdio::null()) // TODO scan output for errors?
+            .spawn()
+            .unwrap(),
+    );
+
+    // dropping the guard kills factorio if interrupted
+    loop {
+        if interrupted
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_ok()
+        {
+            return false;
+        }
+        if factorio.try_wait().unwrap().is_some() {
+            return true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_ini_keeps_user_settings() {
+        let user_config = "; version=11\n\
+            [path]\n\
+            read-data=__PATH__executable__/../../data\n\
+            write-data=__PATH__executable__/../..\n\
+            \n\
+            [graphics]\n\
+            graphics-quality=high\n\
+            ; write-data=unrelated\n";
+        let config = config_ini(user_config, Path::new("/f/data"), Path::new("/tmp/w"));
+        assert_eq!(
+            config,
+            "; version=11\n\
+             [path]\n\
+             read-data=/f/data\n\
+             write-data=/tmp/w\n\
+             \n\
+             [graphics]\n\
+             graphics-quality=high\n\
+             ; write-data=unrelated\n"
+        );
+
+        // installs that were never started have no config.ini yet
+        let config = config_ini("", Path::new("/f/data"), Path::new("/tmp/w"));
+        assert_eq!(config, "[path]\nread-data=/f/data\nwrite-data=/tmp/w\n");
+    }
+}